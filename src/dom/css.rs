@@ -1,15 +1,21 @@
-use std::rc::Rc;
-use std::{char, u32};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::char;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
 
 use regex::{self, Regex, Captures};
 
-use dom::html::{TreeNode, NodeElem};
+use dom::html::{Tree, NodeId, NodeElem, TextKind};
 
 lazy_static! {
     static ref ESCAPE_RE_STR: String = r"\\[^0-9a-fA-F]|\\[0-9a-fA-F]{1,6}".to_owned();
 
     static ref ATTR_RE_STR: String = String::new() +
         r"\[" +
+        r"(?:((?:" + &*ESCAPE_RE_STR + r"|[\w-])*|\*)\|)?" +             // Namespace prefix
         r"((?:" + &*ESCAPE_RE_STR + r"|[\w-])+)" +                     // Key
         r"(?:" +
             r"(\W)?=" +                                                 // Operator
@@ -17,6 +23,12 @@ lazy_static! {
             r"(?:\s+(i))?" +                                            // Case-sensitivity
         r")?" +
         r"\]";
+
+    // Supported pseudo-classes (":first-*" and ":last-*" are rewritten to ":nth-*")
+    static ref PSEUDO_CLASSES: Vec<&'static str> = vec![
+        "root", "empty", "checked", "not", "is", "where", "has", "only-child", "only-of-type",
+        "nth-child", "nth-last-child", "nth-of-type", "nth-last-of-type"
+    ];
 }
 
 #[derive(Debug)]
@@ -27,8 +39,8 @@ pub enum SelectorItem {
 
 #[derive(Debug)]
 pub enum ConditionItem {
-    Tag { name: Regex },
-    Attribute { name: Regex, value: Option<Regex> },
+    Tag { name: Option<Regex>, namespace: Option<Namespace> },
+    Attribute { name: Regex, value: Option<Regex>, namespace: Option<Namespace> },
     PseudoClass {
         class: String,
         group: Option<GroupOfSelectors>,
@@ -36,29 +48,134 @@ pub enum ConditionItem {
     },
 }
 
-pub type Selectors = Vec<Rc<SelectorItem>>;
-pub type GroupOfSelectors = Vec<Rc<Selectors>>;
+#[derive(Debug)]
+pub enum Namespace {
+    Any,
+    Empty,
+    Uri(String),
+}
+
+pub type Selectors = Vec<Arc<SelectorItem>>;
+pub type GroupOfSelectors = Vec<Arc<Selectors>>;
+
+/// A compiled CSS selector, it can be reused for any number of queries (and shared between threads).
+///
+/// ```
+/// use victoria_dom::{DOM, Selector};
+/// let selector = Selector::parse("div > p.big").unwrap();
+/// assert!(Selector::parse("div > p[class=").is_err());
+/// let dom = DOM::new("<div><p class=\"big\">A</p></div>");
+/// assert!(dom.try_at("div > p[class=").is_err());
+/// assert_eq!(dom.try_at("div > p.big").unwrap().unwrap().text(), "A");
+/// ```
+#[derive(Debug, Clone)]
+pub struct Selector {
+    group: GroupOfSelectors,
+}
+
+impl Selector {
+    /// Compile the CSS selector, or return an error if it is invalid.
+    pub fn parse(css: &str) -> Result<Selector, SelectorError> {
+        Selector::parse_with_namespaces(css, &[])
+    }
 
-pub fn matches(tree: &Rc<TreeNode>, css: &str) -> bool {
-    if css.is_empty() { return true; }
-    match tree.elem {
-        NodeElem::Tag { .. } => _match(&parse(css), tree, tree),
-        _ => false
+    /// Compile the CSS selector with namespace prefixes (`prefix|tag` and `[prefix|attr]`) resolved by the prefix2URI list.
+    /// An empty prefix sets the default namespace for type selectors without a prefix.
+    ///
+    /// ```
+    /// use victoria_dom::{DOM, Selector};
+    /// let dom = DOM::new_xml("<feed xmlns=\"http://www.w3.org/2005/Atom\" xmlns:media=\"http://search.yahoo.com/mrss/\">\
+    ///     <entry><media:title>A</media:title><title>B</title></entry></feed>");
+    /// let selector = Selector::parse_with_namespaces("atom|entry > m|title", &[
+    ///     ("atom", "http://www.w3.org/2005/Atom"), ("m", "http://search.yahoo.com/mrss/"),
+    /// ]).unwrap();
+    /// assert_eq!(dom.at_with(&selector).unwrap().text(), "A");
+    /// assert!(Selector::parse("m|title").is_err());
+    /// ```
+    pub fn parse_with_namespaces(css: &str, namespaces: &[(&str, &str)]) -> Result<Selector, SelectorError> {
+        let mut error = None;
+        let group = _parse(css, namespaces, false, &mut error);
+        match error {
+            Some((kind, rest)) => Err(SelectorError { kind, position: rest.as_ptr() as usize - css.as_ptr() as usize }),
+            None => Ok(Selector { group }),
+        }
     }
 }
 
-pub fn select(tree: &Rc<TreeNode>, css: &str, limit: usize) -> Vec<Rc<TreeNode>> {
-    let group = parse(css);
+impl FromStr for Selector {
+    type Err = SelectorError;
+
+    fn from_str(css: &str) -> Result<Selector, SelectorError> {
+        Selector::parse(css)
+    }
+}
+
+/// The kind of a `SelectorError`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectorErrorKind {
+    /// A selector is missing (empty input, empty group member or a dangling combinator).
+    EmptySelector,
+    /// Input that is not a part of a valid selector.
+    UnexpectedToken,
+    /// A malformed attribute selector or unsupported attribute operator.
+    InvalidAttribute,
+    /// A pseudo-class that is not supported.
+    UnknownPseudoClass,
+    /// A missing, unexpected or malformed pseudo-class argument.
+    InvalidArgument,
+    /// A namespace prefix without a registered namespace URI.
+    UnknownNamespace,
+}
+
+/// An error returned for an invalid CSS selector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelectorError {
+    /// The error kind.
+    pub kind: SelectorErrorKind,
+    /// The byte offset of the offending input.
+    pub position: usize,
+}
 
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self.kind {
+            SelectorErrorKind::EmptySelector => "missing selector",
+            SelectorErrorKind::UnexpectedToken => "unexpected token",
+            SelectorErrorKind::InvalidAttribute => "invalid attribute selector",
+            SelectorErrorKind::UnknownPseudoClass => "unknown pseudo-class",
+            SelectorErrorKind::InvalidArgument => "invalid pseudo-class argument",
+            SelectorErrorKind::UnknownNamespace => "unknown namespace prefix",
+        };
+        write!(f, "{} at position {}", reason, self.position)
+    }
+}
+
+impl Error for SelectorError {}
+
+// Compile the CSS selector ignoring errors (invalid parts never match)
+pub fn compile(css: &str) -> Selector {
+    Selector { group: _parse(css, &[], false, &mut None) }
+}
+
+pub fn matches(tree: &Tree, current: NodeId, selector: &Selector) -> bool {
+    tree.is_tag(current) && _match(tree, &Cache::default(), &selector.group, current, current)
+}
+
+// Same as `select` for a single element
+pub fn matches_in_scope(tree: &Tree, current: NodeId, scope: NodeId, selector: &Selector) -> bool {
+    tree.is_tag(current) && _match(tree, &Cache::default(), &selector.group, current, scope)
+}
+
+pub fn select(tree: &Tree, scope: NodeId, selector: &Selector, limit: usize) -> Vec<NodeId> {
     let mut result = Vec::new();
+    let cache = Cache::default();
 
-    let mut queue = tree.get_childs().unwrap();
-    while queue.len() > 0 {
-        let current = queue.remove(0);
-        if let NodeElem::Tag { .. } = current.elem {} else { continue; }
+    let mut next = tree.next_in_scope(scope, scope);
+    while let Some(current) = next {
+        next = tree.next_in_scope(current, scope);
+        if !tree.is_tag(current) { continue; }
 
-        queue = { let mut x = current.get_childs().unwrap(); x.append(&mut queue); x };
-        if (group.is_empty() && css == "*") || _match(&group, &current, tree) { result.push(current); }
+        if _match(tree, &cache, &selector.group, current, scope) { result.push(current); }
 
         if limit > 0 && result.len() == limit { break; }
     }
@@ -66,95 +183,184 @@ pub fn select(tree: &Rc<TreeNode>, css: &str, limit: usize) -> Vec<Rc<TreeNode>>
     result
 }
 
-pub fn select_one(tree: &Rc<TreeNode>, css: &str) -> Option<Rc<TreeNode>> {
-    select(tree, css, 1).pop()
+pub fn select_one(tree: &Tree, scope: NodeId, selector: &Selector) -> Option<NodeId> {
+    select(tree, scope, selector, 1).pop()
 }
 
-fn _match(group: &GroupOfSelectors, current: &Rc<TreeNode>, tree: &Rc<TreeNode>) -> bool {
-    for _selectors in group {
-        let selectors = Rc::new(_selectors.iter().rev().cloned().collect::<Selectors>());
-        if _combinator(&selectors, current, tree, 0) { return true; }
+// Sibling positions for ":nth-*" conditions, counted once per parent element during a query
+#[derive(Default)]
+struct Cache {
+    parents: RefCell<HashSet<(usize, NodeId)>>,
+    positions: RefCell<HashMap<(usize, NodeId), (i64, i64)>>,
+}
+
+impl Cache {
+    // Number of the (same type or matching) siblings before and after the element
+    fn positions(&self, tree: &Tree, condition: &ConditionItem, current: NodeId, of_type: bool, filter: Option<&GroupOfSelectors>) -> (i64, i64) {
+        let key = condition as *const ConditionItem as usize;
+        let parent = match tree.get_parent(current) { Some(x) => x, None => return (0, 0) };
+
+        if self.parents.borrow_mut().insert((key, parent)) {
+            // No borrow is held while matching, the filter can have ":nth-*" conditions too
+            let mut siblings = Vec::new();
+            for x in tree.get_childs(parent) {
+                let name = match tree.get_tag_name(x) { Some(x) => x, None => continue };
+                if filter.is_none_or(|f| _match(tree, self, f, x, tree.root())) {
+                    siblings.push((x, if of_type { &**name } else { "" }));
+                }
+            }
+
+            let mut totals = HashMap::new();
+            for &(_, name) in &siblings { *totals.entry(name).or_insert(0) += 1; }
+            let mut counts = HashMap::new();
+            let mut positions = self.positions.borrow_mut();
+            for (x, name) in siblings {
+                let before = counts.entry(name).or_insert(0);
+                positions.insert((key, x), (*before, totals[name] - *before - 1));
+                *before += 1;
+            }
+        }
+
+        self.positions.borrow().get(&(key, current)).cloned().unwrap_or((0, 0))
+    }
+}
+
+fn _match(tree: &Tree, cache: &Cache, group: &GroupOfSelectors, current: NodeId, scope: NodeId) -> bool {
+    for selectors in group {
+        if _combinator(tree, cache, selectors, current, scope, 0) { return true; }
     }
-    return false;
+    false
 }
 
-fn _combinator(selectors: &Rc<Selectors>, current: &Rc<TreeNode>, tree: &Rc<TreeNode>, mut idx: usize) -> bool {
+// Match the selectors from `idx` on, relative selectors (":has") end with a combinator to the element in `scope`
+fn _combinator(tree: &Tree, cache: &Cache, selectors: &Selectors, current: NodeId, scope: NodeId, mut idx: usize) -> bool {
     if idx >= selectors.len() { return false; }
 
     match *selectors[idx] {
         SelectorItem::Conditions { ref items } => {
-            if !_match_selector_conditions(items, current) { return false; }
+            if !_match_selector_conditions(tree, cache, items, current) { return false; }
 
-            idx = idx + 1;
+            idx += 1;
             if idx >= selectors.len() { return true; }
-            return _combinator(selectors, current, tree, idx);
+            _combinator(tree, cache, selectors, current, scope, idx)
         },
 
         SelectorItem::Combinator { ref op } => {
-            idx = idx + 1;
+            idx += 1;
+            if idx >= selectors.len() { return _is_related(tree, cache, op, current, scope); }
 
             // ">" (parent only)
             if op == ">" {
-                if current.parent.is_none() { return false; }
-                let parent = current.get_parent().unwrap();
+                let parent = match tree.get_parent(current) {
+                    Some(x) => x,
+                    None => return false,
+                };
 
                 // no suitable parent
-                if let NodeElem::Root { .. } = parent.elem { return false; }
-                if parent.id == tree.id { return false; }
+                if !tree.is_tag(parent) { return false; }
+                if parent == scope { return false; }
 
-                return _combinator(selectors, &parent, tree, idx);
+                return _combinator(tree, cache, selectors, parent, scope, idx);
             }
 
             // "~" (preceding siblings)
             if op == "~" {
-                for sibling in _siblings(current, None) {
-                    if sibling.id == current.id { return false; }
-                    if _combinator(selectors, &sibling, tree, idx) { return true; }
+                let mut sibling = _prev_sibling(tree, cache, current, None, None);
+                while let Some(x) = sibling {
+                    if _combinator(tree, cache, selectors, x, scope, idx) { return true; }
+                    sibling = _prev_sibling(tree, cache, x, None, None);
                 }
                 return false;
             }
 
             // "+" (immediately preceding siblings)
             if op == "+" {
-                let mut found = false;
-                for sibling in _siblings(current, None) {
-                    if sibling.id == current.id { return found; }
-                    found = _combinator(selectors, &sibling, tree, idx);
-                }
-                return false;
+                return match _prev_sibling(tree, cache, current, None, None) {
+                    Some(x) => _combinator(tree, cache, selectors, x, scope, idx),
+                    None => false,
+                };
             }
 
             // " " (ancestor)
-            let mut parent = current.get_parent();
-            while parent.is_some() {
-                let current_next = parent.clone().unwrap();
+            let mut parent = tree.get_parent(current);
+            while let Some(current_next) = parent {
+                if !tree.is_tag(current_next) { return false; }
+                if current_next == scope { return false; }
 
-                if let NodeElem::Root { .. } = current_next.elem { return false; }
-                if current_next.id == tree.id { return false; }
+                if _combinator(tree, cache, selectors, current_next, scope, idx) { return true; }
 
-                if _combinator(selectors, &current_next, tree, idx) { return true; }
+                parent = tree.get_parent(current_next);
+            }
+            false
+        },
+    }
+}
 
-                parent = current_next.get_parent();
+// Check if the element is related to the anchor element by the combinator
+fn _is_related(tree: &Tree, cache: &Cache, op: &str, current: NodeId, anchor: NodeId) -> bool {
+    match op {
+        ">" => tree.get_parent(current) == Some(anchor),
+        "+" => _prev_sibling(tree, cache, current, None, None) == Some(anchor),
+        "~" => {
+            let mut sibling = _prev_sibling(tree, cache, current, None, None);
+            while let Some(x) = sibling {
+                if x == anchor { return true; }
+                sibling = _prev_sibling(tree, cache, x, None, None);
+            }
+            false
+        },
+        _ => {
+            let mut parent = tree.get_parent(current);
+            while let Some(x) = parent {
+                if x == anchor { return true; }
+                parent = tree.get_parent(x);
             }
-            return false;
+            false
         },
     }
 }
 
-fn _match_selector_conditions(conditions: &Vec<ConditionItem>, current: &Rc<TreeNode>) -> bool {
+// Elements matching a relative selector of ":has" (descendants or following siblings and their descendants)
+fn _has(tree: &Tree, cache: &Cache, group: &GroupOfSelectors, current: NodeId) -> bool {
+    for selectors in group {
+        let siblings = match *selectors[selectors.len() - 1] {
+            SelectorItem::Combinator { ref op } => op == "+" || op == "~",
+            _ => false,
+        };
+
+        let mut scope = if siblings { _next_sibling(tree, cache, current, None, None) } else { Some(current) };
+        while let Some(x) = scope {
+            if siblings && _combinator(tree, cache, selectors, x, current, 0) { return true; }
+
+            let mut next = tree.next_in_scope(x, x);
+            while let Some(y) = next {
+                next = tree.next_in_scope(y, x);
+                if tree.is_tag(y) && _combinator(tree, cache, selectors, y, current, 0) { return true; }
+            }
+
+            scope = if siblings { _next_sibling(tree, cache, x, None, None) } else { None };
+        }
+    }
+    false
+}
+
+fn _match_selector_conditions(tree: &Tree, cache: &Cache, conditions: &Vec<ConditionItem>, current: NodeId) -> bool {
     'conditem: for ci in conditions {
         match ci {
-            &ConditionItem::Tag { name: ref name_re } => {
-                if !name_re.is_match(current.get_tag_name().unwrap()) { return false; }
+            ConditionItem::Tag { name: name_re, namespace } => {
+                if name_re.as_ref().is_some_and(|x| !x.is_match(tree.get_tag_name(current).unwrap())) { return false; }
+                if namespace.as_ref().is_some_and(|x| !_match_namespace(x, tree.get_namespace(current))) { return false; }
             },
 
-            &ConditionItem::Attribute { name: ref name_re, value: ref value_re } => {
-                let attrs = current.get_tag_attrs().unwrap();
+            ConditionItem::Attribute { name: name_re, value: value_re, namespace } => {
+                let attrs = tree.get_tag_attrs(current).unwrap();
                 let value_re = value_re.as_ref();
 
                 for (name, value) in attrs.iter() {
                     let value = value.as_ref();
 
+                    if !namespace.as_ref().is_none_or(|x| _match_namespace(x, tree.get_attr_namespace(current, name))) { continue; }
+
                     if name_re.is_match(name) && (value.is_none() || value_re.is_none() || value_re.unwrap().is_match(value.unwrap())) {
                         continue 'conditem; // go to a next condition item
                     }
@@ -162,75 +368,82 @@ fn _match_selector_conditions(conditions: &Vec<ConditionItem>, current: &Rc<Tree
                 return false;
             },
 
-            &ConditionItem::PseudoClass { ref class, ref group, ref equation } => {
+            ConditionItem::PseudoClass { class, group, equation } => {
                 // ":empty"
                 if class == "empty" {
-                    let _is_empty = |x: &TreeNode| match x.elem {
-                        NodeElem::Text { ref elem_type, .. } => elem_type == "comment" || elem_type == "pi",
+                    let _is_empty = |x: NodeId| match tree[x].elem {
+                        NodeElem::Text { kind, .. } => kind == TextKind::Comment || kind == TextKind::ProcessingInstruction,
                         _ => false,
                     };
 
-                    let _matched = current.get_childs().unwrap().iter().filter(|&x| !_is_empty(x)).count() == 0;
+                    let _matched = tree.get_childs(current).all(_is_empty);
                     if _matched { continue 'conditem; }
                 }
 
                 // ":root"
                 else if class == "root" {
-                    let parent = current.get_parent();
-                    let _matched = parent.is_some() && match parent.unwrap().elem {
-                        NodeElem::Root { .. } => true,
-                        _ => false
-                    };
+                    let parent = tree.get_parent(current);
+                    let _matched = parent.is_some() && matches!(tree[parent.unwrap()].elem, NodeElem::Root);
                     if _matched { continue 'conditem; }
                 }
 
-                // ":not"
+                // ":not" (selectors relative to the document)
                 else if class == "not" {
-                    let _matched = !_match(&group.clone().unwrap(), current, current);
+                    let _matched = group.as_ref().is_some_and(|x| !_match(tree, cache, x, current, tree.root()));
+                    if _matched { continue 'conditem; }
+                }
+
+                // ":is" or ":where" (selectors relative to the document)
+                else if class == "is" || class == "where" {
+                    let _matched = group.as_ref().is_some_and(|x| _match(tree, cache, x, current, tree.root()));
+                    if _matched { continue 'conditem; }
+                }
+
+                // ":has" (relative selectors)
+                else if class == "has" {
+                    let _matched = group.as_ref().is_some_and(|x| _has(tree, cache, x, current));
                     if _matched { continue 'conditem; }
                 }
 
                 // ":checked"
                 else if class == "checked" {
-                    let _matched = match current.elem {
-                        NodeElem::Tag { ref attrs, .. } => attrs.contains_key("checked") || attrs.contains_key("selected"),
-                        _ => false
+                    let _matched = match tree.get_tag_attrs(current) {
+                        Some(attrs) => attrs.contains_key("checked") || attrs.contains_key("selected"),
+                        None => false
                     };
                     if _matched { continue 'conditem; }
                 }
 
                 // ":nth-child", ":nth-last-child", ":nth-of-type" or ":nth-last-of-type"
-                else if let Some(equation) = *equation {
-                    let mut siblings = if class.ends_with("of-type") {
-                        _siblings(current, Some(current.get_tag_name().unwrap()))
-                    } else {
-                        _siblings(current, None)
-                    };
-
-                    if class.starts_with("nth-last") { siblings.reverse() }
-
-                    for i in 0..siblings.len() {
-                        let result = equation.0 * (i as i32) + equation.1;
-
-                        if result < 1 { continue; }
-                        if (result - 1) as usize >= siblings.len() { break; }
-
-                        if siblings[(result - 1) as usize].id == current.id { continue 'conditem; }
+                else if let Some((a, b)) = *equation {
+                    let name = if class.ends_with("of-type") { tree.get_tag_name(current).map(|x| &**x) } else { None };
+                    let filter = group.as_ref(); // "An+B of S"
+
+                    if filter.is_none_or(|x| _match(tree, cache, x, current, tree.root())) {
+                        // Position among the (same type or matching) siblings, positions above B never match for A <= 0
+                        let last = class.starts_with("nth-last");
+                        let _matched = if a > 0 {
+                            // Every sibling is needed, count them once for all children of the parent
+                            let (before, after) = cache.positions(tree, ci, current, name.is_some(), filter);
+                            let position = if last { after } else { before } + 1;
+                            _is_nth(a, b, position, |_| before + after + 1)
+                        } else {
+                            let position = _count_siblings(tree, cache, current, name, filter, last, i64::from(b)) + 1;
+
+                            // The number of siblings, counted up to the needed one
+                            let len = |needed: i64| position + _count_siblings(tree, cache, current, name, filter, !last, needed - position);
+                            _is_nth(a, b, position, len)
+                        };
+                        if _matched { continue 'conditem; }
                     }
                 }
 
                 // ":only-child" or ":only-of-type"
                 else if class == "only-child" || class == "only-of-type" {
-                    let siblings = if class == "only-of-type" {
-                        _siblings(current, Some(current.get_tag_name().unwrap()))
-                    } else {
-                        _siblings(current, None)
-                    };
-                    for sibling in siblings {
-                        if sibling.id != current.id { return false; }
-                    }
+                    let name = if class == "only-of-type" { tree.get_tag_name(current).map(|x| &**x) } else { None };
 
-                    continue 'conditem;
+                    let _matched = _prev_sibling(tree, cache, current, name, None).is_none() && _next_sibling(tree, cache, current, name, None).is_none();
+                    if _matched { continue 'conditem; }
                 }
 
                 return false;
@@ -238,17 +451,63 @@ fn _match_selector_conditions(conditions: &Vec<ConditionItem>, current: &Rc<Tree
         }
     }
 
-    return true;
+    true
+}
+
+// Check if the position is An+B for some n >= 0 (and n below the number of siblings, B not above it for A < 0)
+fn _is_nth<F: Fn(i64) -> i64>(a: i32, b: i32, position: i64, len: F) -> bool {
+    let (a, b) = (i64::from(a), i64::from(b));
+    let diff = position - b;
+    if a == 0 { return diff == 0; }
+    if diff % a != 0 || diff / a < 0 { return false; }
+    if a > 0 { len(diff / a + 1) > diff / a } else { len(b) >= b }
+}
+
+// Number of the previous (or next) siblings with the given tag name and matching the selectors, up to the limit
+fn _count_siblings(tree: &Tree, cache: &Cache, current: NodeId, name: Option<&str>, filter: Option<&GroupOfSelectors>, next: bool, limit: i64) -> i64 {
+    let mut count = 0;
+    let mut sibling = current;
+    while count < limit {
+        let x = if next { _next_sibling(tree, cache, sibling, name, filter) } else { _prev_sibling(tree, cache, sibling, name, filter) };
+        match x {
+            Some(x) => { count += 1; sibling = x; },
+            None => break,
+        }
+    }
+    count
+}
+
+fn _match_namespace(namespace: &Namespace, uri: Option<&str>) -> bool {
+    match *namespace {
+        Namespace::Any => true,
+        Namespace::Empty => uri.is_none(),
+        Namespace::Uri(ref x) => uri == Some(x),
+    }
+}
+
+// Previous sibling element (with the given tag name and matching the selectors)
+fn _prev_sibling(tree: &Tree, cache: &Cache, current: NodeId, name: Option<&str>, filter: Option<&GroupOfSelectors>) -> Option<NodeId> {
+    let mut sibling = tree[current].prev_sibling;
+    while let Some(x) = sibling {
+        if _is_sibling(tree, cache, x, name, filter) { return Some(x); }
+        sibling = tree[x].prev_sibling;
+    }
+    None
 }
 
-fn _siblings(current: &Rc<TreeNode>, _name: Option<&str>) -> Vec<Rc<TreeNode>> {
-    let parent = current.get_parent().unwrap();
-    let childs = parent.get_childs().unwrap();
+// Next sibling element (with the given tag name and matching the selectors)
+fn _next_sibling(tree: &Tree, cache: &Cache, current: NodeId, name: Option<&str>, filter: Option<&GroupOfSelectors>) -> Option<NodeId> {
+    let mut sibling = tree[current].next_sibling;
+    while let Some(x) = sibling {
+        if _is_sibling(tree, cache, x, name, filter) { return Some(x); }
+        sibling = tree[x].next_sibling;
+    }
+    None
+}
 
-    childs.iter().filter(|&x| match x.elem {
-        NodeElem::Tag { ref name, .. } => if _name.is_some() { name == _name.unwrap() } else { true },
-        _ => false
-    }).cloned().collect()
+fn _is_sibling(tree: &Tree, cache: &Cache, id: NodeId, name: Option<&str>, filter: Option<&GroupOfSelectors>) -> bool {
+    tree.get_tag_name(id).is_some_and(|x| name.is_none_or(|name| &**x == name))
+        && filter.is_none_or(|x| _match(tree, cache, x, id, tree.root()))
 }
 
 fn _unescape(_val: &str) -> String {
@@ -264,7 +523,9 @@ fn _unescape(_val: &str) -> String {
     // Unescape Unicode characters
     val = _RE.replace_all(&val, |caps: &Captures| {
         let hex_char = caps.get(1).unwrap().as_str();
-        format!("{}", char::from_u32(u32::from_str_radix(hex_char, 16).unwrap()).unwrap()).to_owned()
+        // Null, surrogates and code points out of range are replaced
+        let code = u32::from_str_radix(hex_char, 16).unwrap();
+        format!("{}", char::from_u32(code).filter(|&x| x != '\0').unwrap_or('\u{FFFD}'))
     }).into_owned();
 
     // Remove backslash
@@ -278,8 +539,7 @@ fn _name_re(_val: &str) -> Regex {
 }
 
 fn _value_re(op: &str, _val: Option<&str>, insensitive: bool) -> Option<Regex> {
-    if _val.is_none() { return None };
-    let mut value = regex::escape(&_unescape(_val.unwrap()));
+    let mut value = regex::escape(&_unescape(_val?));
 
     if insensitive {
         value = "(?i)".to_owned() + &value.to_owned();
@@ -313,21 +573,43 @@ fn _value_re(op: &str, _val: Option<&str>, insensitive: bool) -> Option<Regex> {
     )).unwrap())
 }
 
-pub fn parse(css: &str) -> GroupOfSelectors {
+// The first error found while parsing and the rest of the input starting at the offending part
+type ParseError<'a> = Option<(SelectorErrorKind, &'a str)>;
+
+fn _error<'a>(error: &mut ParseError<'a>, kind: SelectorErrorKind, css: &'a str) {
+    if error.is_none() { *error = Some((kind, css)); }
+}
+
+// An unmatched or malformed attribute selector is not a missing one
+fn _missing_selector(css: &str) -> SelectorErrorKind {
+    if css.starts_with('[') { SelectorErrorKind::InvalidAttribute } else { SelectorErrorKind::EmptySelector }
+}
+
+// Relative selectors start with a combinator (a descendant combinator if omitted)
+fn _parse<'a>(css: &'a str, namespaces: &[(&str, &str)], relative: bool, error: &mut ParseError<'a>) -> GroupOfSelectors {
     let mut css = css.trim();
 
-    // Group separator re
+    // Group separator and leading combinator re
     lazy_static! {
         static ref _SEPARATOR_RE: Regex = Regex::new(r"^(?s)\s*,\s*(.*)$").unwrap();
+        static ref _LEADING_COMBINATOR_RE: Regex = Regex::new(r"^(?s)([>+~])\s*(.*)$").unwrap();
     }
 
     let mut group: GroupOfSelectors = Vec::new();
     loop {
-        let (selectors, css_rest) = _parse_selectors(css);
+        let mut op = " ";
+        if let Some(caps) = _LEADING_COMBINATOR_RE.captures(css).filter(|_| relative) {
+            op = caps.get(1).unwrap().as_str();
+            css = caps.get(2).unwrap().as_str();
+        }
+
+        let (mut selectors, css_rest) = _parse_selectors(css, namespaces, error);
         if !selectors.is_empty() {
-            group.push(Rc::new(selectors));
+            if relative { selectors.insert(0, Arc::new(SelectorItem::Combinator { op: op.to_owned() })); }
+            group.push(Arc::new(selectors.into_iter().rev().collect())); // reversed for right-to-left matching
             css = css_rest;
         } else {
+            _error(error, _missing_selector(css), css);
             break;
         }
 
@@ -339,10 +621,20 @@ pub fn parse(css: &str) -> GroupOfSelectors {
         }
     }
 
+    // Unparsed rest
+    if !css.is_empty() {
+        let kind = match css.chars().next().unwrap() {
+            '[' => SelectorErrorKind::InvalidAttribute,
+            ':' => SelectorErrorKind::UnknownPseudoClass,
+            _ => SelectorErrorKind::UnexpectedToken,
+        };
+        _error(error, kind, css);
+    }
+
     group
 }
 
-fn _parse_selectors(css: &str) -> (Selectors, &str) {
+fn _parse_selectors<'a>(css: &'a str, namespaces: &[(&str, &str)], error: &mut ParseError<'a>) -> (Selectors, &'a str) {
     let mut css = css;
 
     // Selector combinator re
@@ -352,34 +644,48 @@ fn _parse_selectors(css: &str) -> (Selectors, &str) {
 
     let mut selectors: Selectors = Vec::new();
     loop {
-        let (conditions, css_rest) = _parse_selector_conditions(css);
-        if !conditions.is_empty() {
-            selectors.push(Rc::new(SelectorItem::Conditions { items: conditions }));
+        let (conditions, css_rest) = _parse_selector_conditions(css, namespaces, error);
+        if css_rest.len() < css.len() {
+            selectors.push(Arc::new(SelectorItem::Conditions { items: conditions }));
             css = css_rest;
         } else {
+            // Dangling combinator (whitespace before a group separator is fine)
+            if let Some(SelectorItem::Combinator { op }) = selectors.last().map(|x| &**x) {
+                if op != " " { _error(error, _missing_selector(css), css); }
+                selectors.pop();
+            }
             break;
         }
 
         // Combinator
         if let Some(caps) = _COMBINATOR_RE.captures(css) {
-            selectors.push(Rc::new(SelectorItem::Combinator { op: caps.get(1).unwrap().as_str().to_owned() }));
+            selectors.push(Arc::new(SelectorItem::Combinator { op: caps.get(1).unwrap().as_str().to_owned() }));
             css = caps.get(2).unwrap().as_str();
         } else {
             break;
         }
     }
 
-    return (selectors, css);
+    (selectors, css)
 }
 
-fn _parse_selector_conditions(css: &str) -> (Vec<ConditionItem>, &str) {
+fn _parse_selector_conditions<'a>(css: &'a str, namespaces: &[(&str, &str)], error: &mut ParseError<'a>) -> (Vec<ConditionItem>, &'a str) {
     let mut css = css;
 
     lazy_static! {
         static ref _CLASS_OR_ID_RE: Regex = Regex::new(&(r"^(?s)([.#])((?:".to_owned() + &*ESCAPE_RE_STR + r"\s|\\.|[^,.#:\[ >~+])+)" + r"(.*)$")).unwrap();
         static ref _ATTRIBUTES_RE: Regex = Regex::new(&(r"^(?s)".to_owned() + &*ATTR_RE_STR + r"(.*)$")).unwrap();
-        static ref _PSEUDO_CLASS_RE: Regex = Regex::new(&(r"^(?s):([\w-]+)(?:\(((?:\([^)]+\)|[^)])+)\))?".to_owned() + r"(.*)$")).unwrap();
+        static ref _PSEUDO_CLASS_RE: Regex = Regex::new(r"^(?s):([\w-]+)(.*)$").unwrap();
         static ref _TAG_RE: Regex = Regex::new(&(r"^(?s)((?:".to_owned() + &*ESCAPE_RE_STR + r"\s|\\.|[^,.#:\[ >~+])+)" + r"(.*)$")).unwrap();
+
+        // Valid names (for error reporting)
+        static ref _NAME_RE: Regex = Regex::new(&(r"^(?:".to_owned() + &*ESCAPE_RE_STR + r"\s?|\\.|[\w-]|[^\x00-\x7F])+$")).unwrap();
+
+        // Sibling selectors of ":nth-child" and ":nth-last-child"
+        static ref _OF_RE: Regex = Regex::new(r"^(?is)(.*?)\s+of\s+(.*)$").unwrap();
+
+        // Namespace prefix separator
+        static ref _NAMESPACE_RE: Regex = Regex::new(r"^(?s)((?:\\.|[^\\|])*)\|(.*)$").unwrap();
     }
 
     let mut conditions: Vec<ConditionItem> = Vec::new();
@@ -389,36 +695,54 @@ fn _parse_selector_conditions(css: &str) -> (Vec<ConditionItem>, &str) {
             let prefix = caps.get(1).unwrap().as_str();
             let (name, op) = if prefix == "." { ("class", "~") } else { ("id", "") };
             let op_val = caps.get(2).map(|c| c.as_str());
-            conditions.push(ConditionItem::Attribute { name: _name_re(name), value: _value_re(op, op_val, false) });
+            if !_NAME_RE.is_match(op_val.unwrap()) { _error(error, SelectorErrorKind::UnexpectedToken, css); }
+            conditions.push(ConditionItem::Attribute { name: _name_re(name), value: _value_re(op, op_val, false), namespace: None });
             css = caps.get(3).map(|c| c.as_str()).unwrap_or("");
         }
 
         // Attributes
         else if let Some(caps) = _ATTRIBUTES_RE.captures(css) {
-            let name = caps.get(1).unwrap().as_str();
-            let op = caps.get(2).map(|c| c.as_str()).unwrap_or("");
-            let op_val = caps.get(3).or(caps.get(4)).or(caps.get(5)).map(|c| c.as_str());
-            let op_insensitive = caps.get(6).is_some();
-            conditions.push(ConditionItem::Attribute { name: _name_re(name), value: _value_re(op, op_val, op_insensitive) });
-            css = caps.get(7).map(|c| c.as_str()).unwrap_or("");
+            let prefix = caps.get(1).map(|c| c.as_str());
+            let name = caps.get(2).unwrap().as_str();
+            let op = caps.get(3).map(|c| c.as_str()).unwrap_or("");
+            let op_val = caps.get(4).or(caps.get(5)).or(caps.get(6)).map(|c| c.as_str());
+            let op_insensitive = caps.get(7).is_some();
+            if !["", "~", "*", "^", "$"].contains(&op) { _error(error, SelectorErrorKind::InvalidAttribute, css); }
+            let namespace = prefix.map(|x| _namespace(x, namespaces, error, css)); // no default namespace for attributes
+            conditions.push(ConditionItem::Attribute { name: _name_re(name), value: _value_re(op, op_val, op_insensitive), namespace });
+            css = caps.get(8).map(|c| c.as_str()).unwrap_or("");
         }
 
         // Pseudo-class
         else if let Some(caps) = _PSEUDO_CLASS_RE.captures(css) {
             let name = caps.get(1).unwrap().as_str().to_owned().to_lowercase();
-            let args = caps.get(2).map(|c| c.as_str());
-
-            // ":not" (contains more selectors)
-            if name == "not" {
-                conditions.push(ConditionItem::PseudoClass { class: name, group: args.map(parse), equation: None });
+            let (args, css_rest) = match _args(caps.get(2).unwrap().as_str()) {
+                Some((args, css_rest)) => (Some(args), css_rest),
+                None => (None, caps.get(2).unwrap().as_str()),
+            };
+            let with_group = ["not", "is", "where", "has"].contains(&name.as_str());
+            let with_args = with_group || name.starts_with("nth-");
+
+            // ":not", ":is", ":where" and ":has" (contain more selectors)
+            if with_group {
+                if args.is_none() { _error(error, SelectorErrorKind::InvalidArgument, css); }
+                let relative = name == "has";
+                conditions.push(ConditionItem::PseudoClass { class: name, group: args.map(|x| _parse(x, namespaces, relative, error)), equation: None });
             }
-            // ":nth-*" (with An+B notation)
+            // ":nth-*" (with An+B notation, optionally followed by "of" and selectors for ":nth-child" and ":nth-last-child")
             else if name.starts_with("nth-") {
-                conditions.push(ConditionItem::PseudoClass { class: name, group: None, equation: args.map(_equation) });
+                let (args, of) = match args.and_then(|x| _OF_RE.captures(x)) {
+                    Some(ref caps) if name.ends_with("-child") => (caps.get(1).map(|c| c.as_str()), caps.get(2).map(|c| c.as_str())),
+                    _ => (args, None),
+                };
+                let equation = args.and_then(_equation);
+                if equation.is_none() { _error(error, SelectorErrorKind::InvalidArgument, css); }
+                let group = of.map(|x| _parse(x, namespaces, false, error));
+                conditions.push(ConditionItem::PseudoClass { class: name, group, equation: args.map(|_| equation.unwrap_or((0, 0))) });
             }
             // ":first-*" (rewrite to ":nth-*")
             else if name.starts_with("first-") {
-                let name = "nth-".to_owned() + &name[6..];
+                let name = "nth-".to_owned() + name.trim_start_matches("first-");
                 conditions.push(ConditionItem::PseudoClass { class: name, group: None, equation: Some((0, 1)) });
             }
             // ":last-*" (rewrite to ":nth-*")
@@ -431,14 +755,39 @@ fn _parse_selector_conditions(css: &str) -> (Vec<ConditionItem>, &str) {
                 conditions.push(ConditionItem::PseudoClass { class: name, group: None, equation: None });
             }
 
-            css = caps.get(3).map(|c| c.as_str()).unwrap_or("");
+            // Validate the name and arguments
+            let class = match conditions.last() { Some(ConditionItem::PseudoClass { class, .. }) => class.as_str(), _ => "" };
+            if !PSEUDO_CLASSES.contains(&class) {
+                _error(error, SelectorErrorKind::UnknownPseudoClass, css);
+            } else if args.is_some() && !with_args {
+                _error(error, SelectorErrorKind::InvalidArgument, css);
+            }
+
+            css = css_rest;
         }
 
         // Tag
         else if let Some(caps) = _TAG_RE.captures(css) {
-            let name = caps.get(1).unwrap().as_str();
-            if name != "*" {
-                conditions.push(ConditionItem::Tag { name: _name_re(name) });
+            let mut name = caps.get(1).unwrap().as_str();
+
+            // Namespace prefix ("ns|tag", "*|tag" or "|tag")
+            let mut prefix = None;
+            if let Some(ns_caps) = _NAMESPACE_RE.captures(name) {
+                prefix = ns_caps.get(1).map(|c| c.as_str());
+                name = ns_caps.get(2).unwrap().as_str();
+            }
+
+            let _is_valid = |x: &str| x == "*" || _NAME_RE.is_match(x);
+            if !_is_valid(name) || !prefix.is_none_or(|x| x.is_empty() || _is_valid(x)) {
+                _error(error, SelectorErrorKind::UnexpectedToken, css);
+            }
+
+            let namespace = match prefix {
+                Some(x) => Some(_namespace(x, namespaces, error, css)),
+                None => namespaces.iter().find(|x| x.0.is_empty()).map(|x| Namespace::Uri(x.1.to_owned())), // default namespace
+            };
+            if name != "*" || namespace.is_some() {
+                conditions.push(ConditionItem::Tag { name: if name != "*" { Some(_name_re(name)) } else { None }, namespace });
             }
             css = caps.get(2).map(|c| c.as_str()).unwrap_or("");
         }
@@ -446,39 +795,78 @@ fn _parse_selector_conditions(css: &str) -> (Vec<ConditionItem>, &str) {
         else { break; }
     }
 
-    return (conditions, css);
+    (conditions, css)
+}
+
+// Pseudo-class arguments in balanced parentheses and the rest of the input
+fn _args(css: &str) -> Option<(&str, &str)> {
+    if !css.starts_with('(') { return None; }
+
+    let mut depth = 0;
+    let mut quote = None;
+    let mut chars = css.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => { chars.next(); },
+            '"' | '\'' if quote.is_none() => quote = Some(c),
+            _ if quote == Some(c) => quote = None,
+            _ if quote.is_some() => {},
+            '(' => depth += 1,
+            ')' => {
+                depth -= 1;
+                if depth == 0 { return Some((&css[1 .. i], &css[i + 1 ..])); }
+            },
+            _ => {},
+        }
+    }
+    None
+}
+
+// Resolve the namespace prefix ("*" is any namespace and an empty prefix is no namespace)
+fn _namespace<'a>(prefix: &str, namespaces: &[(&str, &str)], error: &mut ParseError<'a>, css: &'a str) -> Namespace {
+    if prefix == "*" { return Namespace::Any; }
+    if prefix.is_empty() { return Namespace::Empty; }
+
+    let prefix = _unescape(prefix);
+    match namespaces.iter().find(|x| x.0 == prefix) {
+        Some(x) => Namespace::Uri(x.1.to_owned()),
+        None => {
+            _error(error, SelectorErrorKind::UnknownNamespace, css);
+            Namespace::Uri(prefix) // unknown prefix is used as the namespace URI
+        },
+    }
 }
 
-fn _equation(equation_str: &str) -> (i32, i32) {
+fn _equation(equation_str: &str) -> Option<(i32, i32)> {
     lazy_static! {
         static ref _RE1: Regex = Regex::new(r"^\s*((?:\+|-)?\d+)\s*$").unwrap();
         static ref _RE2: Regex = Regex::new(r"^(?i)\s*((?:\+|-)?(?:\d+)?)?n\s*((?:\+|-)\s*\d+)?\s*$").unwrap();
     }
 
-    if equation_str.is_empty() { return (0, 0); }
+    if equation_str.is_empty() { return None; }
 
     // "even"
-    if equation_str.trim().to_lowercase() == "even" { return (2, 2); }
+    if equation_str.trim().to_lowercase() == "even" { return Some((2, 2)); }
 
     // "odd"
-    if equation_str.trim().to_lowercase() == "odd" { return (2, 1); }
+    if equation_str.trim().to_lowercase() == "odd" { return Some((2, 1)); }
 
     // "4", "+4" or "-4"
     if let Some(caps) = _RE1.captures(equation_str) {
-        let num = caps.get(1).unwrap().as_str().parse::<i32>().unwrap();
-        return (0, num);
+        let num = caps.get(1).unwrap().as_str().parse::<i32>().ok()?;
+        return Some((0, num));
     }
 
     // "n", "4n", "+4n", "-4n", "n+1", "4n-1", "+4n-1" (and other variations)
     if let Some(caps) = _RE2.captures(equation_str) {
         let mut result = (0, 0);
         let num1 = caps.get(1).unwrap().as_str();
-        result.0 = if num1 == "-" { -1 } else if num1.is_empty() { 1 } else { num1.parse::<i32>().unwrap() };
+        result.0 = if num1 == "-" { -1 } else if num1.is_empty() || num1 == "+" { 1 } else { num1.parse::<i32>().ok()? };
         if let Some(num2) = caps.get(2) {
-            result.1 = num2.as_str().split_whitespace().collect::<Vec<&str>>().concat().parse::<i32>().unwrap();
+            result.1 = num2.as_str().split_whitespace().collect::<Vec<&str>>().concat().parse::<i32>().ok()?;
         }
-        return result;
+        return Some(result);
     }
 
-    return (0, 0);
+    None
 }
@@ -0,0 +1,60 @@
+use std::borrow::Cow;
+
+use encoding_rs::{Encoding, UTF_8, UTF_16BE, UTF_16LE, WINDOWS_1252, X_USER_DEFINED};
+use regex::Regex;
+
+use super::html;
+use super::tokenizer::{Tokenizer, TokenKind};
+
+// Number of bytes searched for a meta element
+const PRESCAN_LEN: usize = 1024;
+
+// Decode a document with the encoding from the BOM, the override label, a meta element
+// or UTF-8 if it is valid (windows-1252 otherwise)
+pub fn decode<'a>(bytes: &'a [u8], label: Option<&str>) -> (Cow<'a, str>, &'static Encoding) {
+    let encoding = Encoding::for_bom(bytes).map(|x| x.0)
+        .or_else(|| label.and_then(|x| Encoding::for_label(x.as_bytes())))
+        .or_else(|| _prescan(bytes))
+        .unwrap_or_else(|| if ::std::str::from_utf8(bytes).is_ok() { UTF_8 } else { WINDOWS_1252 });
+
+    let (text, encoding, _) = encoding.decode(bytes);
+    (text, encoding)
+}
+
+// Encoding of the first meta element with a charset
+fn _prescan(bytes: &[u8]) -> Option<&'static Encoding> {
+    // Markup is ASCII in all supported encodings
+    let prefix: String = bytes.iter().take(PRESCAN_LEN).map(|&x| x as char).collect();
+
+    for token in Tokenizer::new(&prefix) {
+        if let TokenKind::StartTag { name, ref attrs, .. } = token.kind {
+            let (name, attrs) = html::start_tag(name, attrs, false);
+            if name != "meta" { continue; }
+
+            let label = match (attrs.get("charset"), attrs.get("http-equiv"), attrs.get("content")) {
+                (Some(Some(charset)), _, _) => Some(charset.clone()),
+                (_, Some(Some(equiv)), Some(Some(content))) if equiv.eq_ignore_ascii_case("content-type") => {
+                    _content_charset(content)
+                },
+                _ => None,
+            };
+
+            if let Some(encoding) = label.and_then(|x| Encoding::for_label(x.trim().as_bytes())) {
+                // A meta element can't switch to an encoding that is not ASCII compatible
+                if encoding == UTF_16BE || encoding == UTF_16LE { return Some(UTF_8); }
+                if encoding == X_USER_DEFINED { return Some(WINDOWS_1252); }
+                return Some(encoding);
+            }
+        }
+    }
+    None
+}
+
+// The charset parameter of a Content-Type value
+fn _content_charset(content: &str) -> Option<String> {
+    lazy_static! {
+        static ref _CHARSET_RE: Regex = Regex::new(r#"(?i)charset\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s;"']+))"#).unwrap();
+    }
+    let captures = _CHARSET_RE.captures(content)?;
+    (1 .. 4).filter_map(|i| captures.get(i)).next().map(|x| x.as_str().to_owned())
+}
@@ -0,0 +1,227 @@
+use std::collections::VecDeque;
+use std::io::{self, Read};
+use std::sync::Arc;
+
+use super::html::{self, Attrs, OpenElements, PendingInput, TextKind};
+use super::tokenizer::{TokenBuffer, TokenKind};
+use util::{html_unescape, utf8_decode};
+
+/// A parser event, see `EventParser`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// An element has been opened.
+    StartElement {
+        /// The tag name.
+        name: String,
+        /// The attributes in source order.
+        attrs: Attrs,
+    },
+    /// An element has been closed, by its end tag or implicitly.
+    EndElement {
+        /// The tag name.
+        name: String,
+    },
+    /// A text, comment, CDATA, doctype or processing instruction node.
+    Text {
+        /// The node kind.
+        kind: TextKind,
+        /// The content, with character references decoded (for text).
+        content: String,
+    },
+}
+
+/// Streaming push parser emitting `Event`s instead of building a tree.
+///
+/// The events describe the tree `DOM::new` would build, including elements closed implicitly.
+///
+/// ```
+/// use victoria_dom::{Event, EventParser, TextKind};
+/// let mut parser = EventParser::new();
+/// parser.feed("<ul><li>A &am");
+/// parser.feed("p; B<li>C</ul>");
+/// parser.finish();
+///
+/// let events: Vec<_> = parser.map(|x| match x {
+///     Event::StartElement { name, .. } => format!("<{}>", name),
+///     Event::EndElement { name } => format!("</{}>", name),
+///     Event::Text { kind: TextKind::Text, content } => content,
+///     Event::Text { .. } => String::new(),
+/// }).collect();
+/// assert_eq!(events.concat(), "<ul><li>A & B</li><li>C</li></ul>");
+/// ```
+#[derive(Debug)]
+pub struct EventParser {
+    pending: PendingInput, // input before XML mode has been detected
+    tokens: Option<TokenBuffer>,
+    open: OpenElements<()>,
+    events: VecDeque<Event>,
+    xml: bool,
+    rcdata: bool, // raw text of the last start tag contains entities
+}
+
+impl EventParser {
+    /// Construct a new `EventParser` for HTML (or XML, if the document starts with an XML declaration).
+    pub fn new() -> EventParser {
+        EventParser { tokens: None, ..EventParser::_new(false) }
+    }
+
+    /// Construct a new `EventParser` for XML.
+    pub fn new_xml() -> EventParser {
+        EventParser::_new(true)
+    }
+
+    fn _new(xml: bool) -> EventParser {
+        EventParser { pending: PendingInput::default(), tokens: Some(TokenBuffer::new(xml)), open: OpenElements::new(false, xml), events: VecDeque::new(), xml, rcdata: false }
+    }
+
+    /// Parse the next chunk of input, events become available as soon as they are complete.
+    ///
+    /// An incomplete tag (or comment) is scanned again once at least as much new input has arrived,
+    /// so the whole input is parsed in linear time.
+    pub fn feed(&mut self, chunk: &str) {
+        self._process(chunk, false);
+    }
+
+    /// Parse the rest of the input and close all open elements.
+    pub fn finish(&mut self) {
+        self._process("", true);
+        for (_, name) in self.open.finish().into_iter().rev() {
+            self.events.push_back(Event::EndElement { name: name.to_string() });
+        }
+    }
+
+    fn _process(&mut self, chunk: &str, finish: bool) {
+        match self.tokens {
+            Some(ref mut tokens) => tokens.push(chunk),
+            None => match self.pending.push(chunk, finish) {
+                Some(tokens) => {
+                    self.xml = tokens.is_xml();
+                    self.open = OpenElements::new(false, self.xml);
+                    self.tokens = Some(tokens);
+                },
+                None => return,
+            },
+        }
+
+        let EventParser { ref mut tokens, ref mut open, ref mut events, xml, ref mut rcdata, .. } = *self;
+        let tokens = tokens.as_mut().unwrap();
+        let _end_elements = |events: &mut VecDeque<Event>, closed: Vec<((), Arc<str>)>| {
+            for (_, name) in closed.into_iter().rev() { events.push_back(Event::EndElement { name: name.to_string() }); }
+        };
+
+        let _: Result<(), ()> = tokens.tokens(finish, |token| {
+            match token.kind {
+                TokenKind::Text(text) => {
+                    events.push_back(Event::Text { kind: TextKind::Text, content: html_unescape(text) });
+                },
+                TokenKind::RawText(text) => {
+                    let content = if *rcdata { html_unescape(text) } else { text.to_owned() };
+                    events.push_back(Event::Text { kind: TextKind::Raw, content });
+                },
+                TokenKind::EndTag(end_tag) => {
+                    if let Some(closed) = open.end(&html::tag_name(end_tag, xml)) { _end_elements(events, closed); }
+                },
+                TokenKind::StartTag { name, ref attrs, self_closing } => {
+                    let (name, attrs) = html::start_tag(name, attrs, xml);
+                    *rcdata = html::RCDATA.contains(name.as_str());
+
+                    let closed = open.start(&name);
+                    _end_elements(events, closed);
+                    events.push_back(Event::StartElement { name: name.clone(), attrs });
+                    open.push((), Arc::from(name.as_str()));
+
+                    if html::is_closed_by_start_tag(&name, self_closing, xml) {
+                        if let Some(closed) = open.end(&name) { _end_elements(events, closed); }
+                    }
+                },
+                TokenKind::Comment(text) => events.push_back(Event::Text { kind: TextKind::Comment, content: text.to_owned() }),
+                TokenKind::Doctype(text) => events.push_back(Event::Text { kind: TextKind::Doctype, content: text.to_owned() }),
+                TokenKind::CData(text) => events.push_back(Event::Text { kind: TextKind::CData, content: text.to_owned() }),
+                TokenKind::ProcessingInstruction(text) => {
+                    events.push_back(Event::Text { kind: TextKind::ProcessingInstruction, content: text.to_owned() });
+                },
+            }
+            Ok(())
+        });
+    }
+}
+
+impl Default for EventParser {
+    fn default() -> EventParser {
+        EventParser::new()
+    }
+}
+
+impl Iterator for EventParser {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+}
+
+/// Streaming pull parser reading UTF-8 input and emitting `Event`s (see `EventParser`).
+///
+/// Invalid UTF-8 is replaced with `U+FFFD`, like `Parser` does.
+///
+/// ```
+/// use victoria_dom::{Event, EventReader};
+/// let reader = EventReader::new("<p>A<p>B".as_bytes());
+/// let names: Vec<_> = reader.filter_map(|x| match x.unwrap() {
+///     Event::StartElement { name, .. } => Some(name),
+///     _ => None,
+/// }).collect();
+/// assert_eq!(names, ["p", "p"]);
+/// ```
+#[derive(Debug)]
+pub struct EventReader<R> {
+    reader: R,
+    parser: EventParser,
+    bytes: Vec<u8>, // incomplete UTF-8 sequence
+    done: bool,
+}
+
+impl<R: Read> EventReader<R> {
+    /// Construct a new `EventReader` for HTML (or XML, if the document starts with an XML declaration).
+    pub fn new(reader: R) -> EventReader<R> {
+        EventReader { reader, parser: EventParser::new(), bytes: Vec::new(), done: false }
+    }
+
+    /// Construct a new `EventReader` for XML.
+    pub fn new_xml(reader: R) -> EventReader<R> {
+        EventReader { reader, parser: EventParser::new_xml(), bytes: Vec::new(), done: false }
+    }
+
+    fn _read(&mut self) -> io::Result<()> {
+        let mut chunk = [0; 8192];
+        let len = match self.reader.read(&mut chunk) {
+            Ok(x) => x,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        // End of input
+        if len == 0 {
+            self.done = true;
+            self.parser.feed(&String::from_utf8_lossy(&self.bytes));
+            self.parser.finish();
+            return Ok(());
+        }
+
+        self.bytes.extend_from_slice(&chunk[.. len]);
+        self.parser.feed(&utf8_decode(&mut self.bytes));
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for EventReader<R> {
+    type Item = io::Result<Event>;
+
+    fn next(&mut self) -> Option<io::Result<Event>> {
+        loop {
+            if let Some(event) = self.parser.next() { return Some(Ok(event)); }
+            if self.done { return None; }
+            if let Err(e) = self._read() { return Some(Err(e)); }
+        }
+    }
+}
@@ -1,53 +1,23 @@
-use std::collections::{HashSet, HashMap, BTreeMap};
-use std::rc::{Rc, Weak};
-use std::cell::RefCell;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::cmp;
+use std::collections::{HashSet, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::iter::FromIterator;
+use std::mem;
+use std::ops::{Index, IndexMut, Range};
+use std::sync::{Arc, Mutex, Weak};
 
-use regex::{self, Regex};
+use regex::Regex;
 
-use util::{xml_escape, html_unescape, html_attr_unescape};
+use super::tokenizer::{Token, TokenBuffer, Tokenizer, TokenKind};
+use util::{xml_escape, html_unescape, html_attr_unescape, find_bad_entity};
 
 lazy_static! {
-    static ref ATTR_RE_STR: String = String::new() +
-        r"([^<>=\s/]+|/)" +         // Key
-        r"(?:" +
-            r"\s*=\s*" +
-            r"(?s:" +
-                r#""(.*?)""# +      // Quotation marks
-            r"|" +
-                r"'(.*?)'" +        // Apostrophes
-            r"|" +
-                r"([^>\s]*)" +      // Unquoted
-            r")" +
-        r")?\s*";
-
-    static ref TOKEN_RE_STR: String = String::new() +
-        r"(?is)" +
-        r"([^<]+)?" +                                               // Text
-        r"(?:" +
-            r"<(?:" +
-                r"!(?:" +
-                    r"DOCTYPE(\s+\w+.*?)" +                         // Doctype
-                r"|" +
-                    r"--(.*?)--\s*" +                               // Comment
-                r"|" +
-                    r"\[CDATA\[(.*?)\]\]" +                         // CDATA
-                r")" +
-            r"|" +
-                r"\?(.*?)\?" +                                      // Processing Instruction
-            r"|" +
-                r"\s*([^<>\s]+\s*(?:" + &*ATTR_RE_STR + r")*)" +    // Tag
-            r")>" +
-        r"|" +
-            r"(<)" +                                                // Runaway "<"
-        r")?" +
-        r"(.*)$";                                                   // Rest of html
-
     // HTML elements that only contain raw text
-    static ref RAW: HashSet<&'static str> = hashset!["script", "style"];
+    pub static ref RAW: HashSet<&'static str> = hashset!["script", "style"];
 
     // HTML elements that only contain raw text and entities
-    static ref RCDATA: HashSet<&'static str> = hashset!["title", "textarea"];
+    pub static ref RCDATA: HashSet<&'static str> = hashset!["title", "textarea"];
 
     static ref END: HashMap<&'static str, &'static str> = {
         // HTML elements with optional end tags
@@ -92,6 +62,12 @@ lazy_static! {
         _close
     };
 
+    // HTML elements with optional end tags (strict mode)
+    static ref OPTIONAL: HashSet<&'static str> = hashset![
+        "body", "colgroup", "dd", "dt", "head", "html", "li", "optgroup", "option", "p", "rp", "rt",
+        "tbody", "td", "tfoot", "th", "thead", "tr"
+    ];
+
     // HTML elements without end tags
     static ref EMPTY: HashSet<&'static str> = hashset![
         "area", "base", "br", "col", "embed", "hr", "img", "input", "keygen", "link",
@@ -121,349 +97,1415 @@ lazy_static! {
     ];
 }
 
-static NODE_ID_NEXT: AtomicUsize = AtomicUsize::new(0);
+const XML_NAMESPACE: &str = "http://www.w3.org/XML/1998/namespace";
+const HTML_NAMESPACE: &str = "http://www.w3.org/1999/xhtml";
+const SVG_NAMESPACE: &str = "http://www.w3.org/2000/svg";
+const MATHML_NAMESPACE: &str = "http://www.w3.org/1998/Math/MathML";
+
+/// Index of a node in the `Tree` arena.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(u32);
 
 #[derive(Debug)]
-pub struct TreeNode {
-    pub id: usize,
-    pub parent: Option<Weak<TreeNode>>,
+pub struct Node {
+    pub parent: Option<NodeId>,
+    pub prev_sibling: Option<NodeId>,
+    pub next_sibling: Option<NodeId>,
+    pub first_child: Option<NodeId>,
+    pub last_child: Option<NodeId>,
     pub elem: NodeElem,
+    pub span: Option<NodeSpan>,
+    pub source: Option<Source>,
 }
 
-#[derive(Debug)]
+// Byte ranges of the source tokens of a parsed node (followed by ignored tokens, like stray end tags)
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub open: Range<usize>,
+    pub close: Option<Range<usize>>,
+    pub modified: bool, // attributes changed, the start tag is rendered again
+}
+
+#[derive(Debug, Clone)]
 pub enum NodeElem {
-    Root {
-        childs: RefCell<Vec<Rc<TreeNode>>>,
-    },
+    Root,
 
     Tag {
-        name: String,
-        attrs: BTreeMap<String, Option<String>>,
-        childs: RefCell<Vec<Rc<TreeNode>>>,
+        name: Arc<str>,
+        attrs: Attrs,
     },
 
     Text {
-        elem_type: String,
+        kind: TextKind,
         content: String,
     },
 }
 
-impl TreeNode {
-    pub fn is_tag(&self) -> bool {
-        match self.elem {
-            NodeElem::Tag { .. } => true,
+/// Element attributes in source order.
+///
+/// An attribute repeated in the source keeps the position of its first occurrence and the value
+/// of the last one, the overridden occurrences are available with `duplicates`.
+///
+/// ```
+/// use victoria_dom::DOM;
+/// let dom = DOM::new("<p id=\"a\" class=\"x\" hidden id=\"b\"></p>");
+/// let attrs = dom.at("p").unwrap().attrs();
+/// assert_eq!(attrs.keys().collect::<Vec<_>>(), ["id", "class", "hidden"]);
+/// assert_eq!(attrs.get("id").unwrap().as_deref(), Some("b"));
+/// assert_eq!(attrs.duplicates(), [("id".to_owned(), Some("a".to_owned()))]);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Attrs {
+    items: Vec<(String, Option<String>)>,
+    duplicates: Vec<(String, Option<String>)>,
+}
+
+impl Attrs {
+    /// Create an empty attribute list.
+    pub fn new() -> Attrs {
+        Attrs::default()
+    }
+
+    /// The attribute value (`Some(None)` for an attribute without value).
+    pub fn get(&self, name: &str) -> Option<&Option<String>> {
+        self.items.iter().find(|x| x.0 == name).map(|x| &x.1)
+    }
+
+    /// Check if there is an attribute with the name.
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.items.iter().any(|x| x.0 == name)
+    }
+
+    /// Replace the value of an existing attribute (keeping its position), or add a new one to the end.
+    pub fn insert(&mut self, name: String, value: Option<String>) {
+        match self.items.iter_mut().find(|x| x.0 == name) {
+            Some(x) => x.1 = value,
+            None => self.items.push((name, value)),
+        }
+    }
+
+    /// Remove the attribute.
+    pub fn remove(&mut self, name: &str) {
+        self.items.retain(|x| x.0 != name);
+    }
+
+    /// The number of attributes.
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    /// Check if there are no attributes.
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Iterate over the attribute names and values.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Option<String>)> {
+        self.items.iter().map(|x| (&x.0, &x.1))
+    }
+
+    /// Iterate over the attribute names.
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.items.iter().map(|x| &x.0)
+    }
+
+    /// Attribute occurrences overridden by a later one with the same name, in source order.
+    pub fn duplicates(&self) -> &[(String, Option<String>)] {
+        &self.duplicates
+    }
+
+    // Add a parsed attribute, recording the overridden occurrence
+    fn parsed(&mut self, name: String, value: Option<String>) {
+        if let Some(x) = self.items.iter_mut().find(|x| x.0 == name) {
+            let old = mem::replace(&mut x.1, value);
+            self.duplicates.push((name, old));
+            return;
+        }
+        self.items.push((name, value));
+    }
+}
+
+impl Extend<(String, Option<String>)> for Attrs {
+    fn extend<T: IntoIterator<Item = (String, Option<String>)>>(&mut self, iter: T) {
+        for (name, value) in iter { self.insert(name, value); }
+    }
+}
+
+impl FromIterator<(String, Option<String>)> for Attrs {
+    fn from_iter<T: IntoIterator<Item = (String, Option<String>)>>(iter: T) -> Attrs {
+        let mut attrs = Attrs::new();
+        attrs.extend(iter);
+        attrs
+    }
+}
+
+impl IntoIterator for Attrs {
+    type Item = (String, Option<String>);
+    type IntoIter = ::std::vec::IntoIter<(String, Option<String>)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.into_iter()
+    }
+}
+
+// Arena holding all nodes of a document, the root node is always the first one.
+// Removed subtrees are kept in the arena while a `DOM` object (pinned node) refers to them,
+// afterwards their slots are reused for new nodes.
+#[derive(Debug)]
+pub struct Tree {
+    nodes: Vec<Node>,
+    free: Vec<NodeId>, // slots of collected nodes
+    removed: Vec<NodeId>, // roots of removed subtrees waiting to be collected
+    kept: usize, // removed subtrees still pinned at the last collection
+    pins: Mutex<Pins>,
+    pub xml: bool,
+    pub source: Option<Arc<str>>, // lossless mode only
+    pub encoding: Option<&'static str>, // documents parsed from bytes only
+}
+
+impl Index<NodeId> for Tree {
+    type Output = Node;
+
+    fn index(&self, id: NodeId) -> &Node {
+        &self.nodes[id.0 as usize]
+    }
+}
+
+impl IndexMut<NodeId> for Tree {
+    fn index_mut(&mut self, id: NodeId) -> &mut Node {
+        &mut self.nodes[id.0 as usize]
+    }
+}
+
+impl Tree {
+    pub fn new() -> Tree {
+        let mut tree = Tree { nodes: Vec::new(), free: Vec::new(), removed: Vec::new(), kept: 0, pins: Mutex::new(Pins::default()), xml: false, source: None, encoding: None };
+        tree.create(NodeElem::Root);
+        tree
+    }
+
+    pub fn root(&self) -> NodeId {
+        NodeId(0)
+    }
+
+    pub fn create(&mut self, elem: NodeElem) -> NodeId {
+        let node = Node { parent: None, prev_sibling: None, next_sibling: None, first_child: None, last_child: None, elem, span: None, source: None };
+        match self.free.pop() {
+            Some(id) => {
+                self[id] = node;
+                id
+            },
+            None => {
+                self.nodes.push(node);
+                NodeId(self.nodes.len() as u32 - 1)
+            },
+        }
+    }
+
+    // Number of slots in the arena (including free ones)
+    #[cfg(test)]
+    pub fn capacity(&self) -> usize {
+        self.nodes.len()
+    }
+
+    // Keep the node (and the subtree it belongs to after removal) in the arena while the pin is alive
+    pub fn pin(&self, id: NodeId) -> Arc<()> {
+        let mut pins = self.pins.lock().unwrap();
+        if let Some(pin) = pins.map.get(&id).and_then(Weak::upgrade) { return pin; }
+
+        // Forget dropped pins from time to time
+        if pins.map.len() >= pins.limit {
+            pins.map.retain(|_, x| x.strong_count() > 0);
+            pins.limit = cmp::max(64, pins.map.len() * 2);
+        }
+
+        let pin = Arc::new(());
+        pins.map.insert(id, Arc::downgrade(&pin));
+        pin
+    }
+
+    // Detach a node that is no longer used by the document, its subtree gets collected once it isn't pinned
+    pub fn remove(&mut self, id: NodeId) {
+        self.detach(id);
+        self.removed.push(id);
+    }
+
+    // Move the nodes of removed subtrees without pins to the free list, once enough subtrees have been removed
+    // since the last collection (pinned subtrees are not walked again on every mutation)
+    fn collect(&mut self) {
+        if self.removed.len() < cmp::max(16, self.kept * 2) { return; }
+
+        let pins = mem::take(&mut *self.pins.lock().unwrap());
+        let _is_pinned = |x: &NodeId| pins.map.get(x).is_some_and(|x| x.strong_count() > 0);
+
+        for id in mem::take(&mut self.removed) {
+            // Inserted again
+            if self[id].parent.is_some() { continue; }
+
+            // Stop at the first pinned node
+            let mut subtree = Vec::new();
+            let mut current = Some(id);
+            while let Some(x) = current.filter(|x| !_is_pinned(x)) {
+                subtree.push(x);
+                current = self.next_in_scope(x, id);
+            }
+            if current.is_some() {
+                self.removed.push(id);
+                continue;
+            }
+
+            for x in subtree {
+                self[x] = Node { parent: None, prev_sibling: None, next_sibling: None, first_child: None, last_child: None, elem: NodeElem::Root, span: None, source: None };
+                self.free.push(x);
+            }
+        }
+
+        self.kept = self.removed.len();
+        *self.pins.lock().unwrap() = pins;
+    }
+
+    pub fn get_node_type(&self, id: NodeId) -> NodeType {
+        match self[id].elem {
+            NodeElem::Root => NodeType::Root,
+            NodeElem::Tag { .. } => NodeType::Tag,
+            NodeElem::Text { kind, .. } => match kind {
+                TextKind::Text | TextKind::Raw => NodeType::Text,
+                TextKind::Comment => NodeType::Comment,
+                TextKind::CData => NodeType::CData,
+                TextKind::Doctype => NodeType::Doctype,
+                TextKind::ProcessingInstruction => NodeType::ProcessingInstruction,
+            },
+        }
+    }
+
+    // Namespace URI declared for the prefix (or the default namespace) in scope of the node
+    pub fn lookup_namespace(&self, id: NodeId, prefix: Option<&str>) -> Option<&str> {
+        if prefix == Some("xml") { return Some(XML_NAMESPACE); }
+
+        let attr = prefix.map_or_else(|| "xmlns".to_owned(), |x| "xmlns:".to_owned() + x);
+        let mut node = Some(id);
+        while let Some(x) = node {
+            if let Some(value) = self.get_tag_attrs(x).and_then(|attrs| attrs.get(&attr)) {
+                return value.as_deref().filter(|x| !x.is_empty());
+            }
+            node = self.get_parent(x);
+        }
+        None
+    }
+
+    pub fn get_namespace(&self, id: NodeId) -> Option<&str> {
+        let name = self.get_tag_name(id)?;
+        let prefix = name.find(':').map(|x| &name[.. x]);
+        let namespace = self.lookup_namespace(id, prefix);
+        if namespace.is_some() || prefix.is_some() || self.xml { return namespace; }
+
+        // Implicit namespaces of HTML elements and SVG and MathML elements embedded in HTML,
+        // content of their integration points is HTML again
+        let _is_integration_point = |x: NodeId, name: &str| match name {
+            "foreignobject" | "desc" | "title" => self.get_namespace(x) == Some(SVG_NAMESPACE),
+            "mi" | "mo" | "mn" | "ms" | "mtext" => self.get_namespace(x) == Some(MATHML_NAMESPACE),
             _ => false,
+        };
+        let mut node = Some(id);
+        while let Some(x) = node {
+            match self.get_tag_name(x).map(|x| &**x) {
+                Some("svg") => return Some(SVG_NAMESPACE),
+                Some("math") => return Some(MATHML_NAMESPACE),
+                Some(name) if x != id && _is_integration_point(x, name) => break,
+                _ => node = self.get_parent(x),
+            }
         }
+        Some(HTML_NAMESPACE)
     }
 
-    pub fn get_tag_name(&self) -> Option<&str> {
-        match self.elem {
-            NodeElem::Tag { ref name, .. } => Some(name),
+    pub fn get_attr_namespace(&self, id: NodeId, name: &str) -> Option<&str> {
+        name.find(':').and_then(|x| self.lookup_namespace(id, Some(&name[.. x])))
+    }
+
+    pub fn get_text_kind(&self, id: NodeId) -> Option<TextKind> {
+        match self[id].elem {
+            NodeElem::Text { kind, .. } => Some(kind),
             _ => None,
         }
     }
 
-    pub fn get_tag_attrs<'a>(&'a self) -> Option<&'a BTreeMap<String, Option<String>>> {
-        match self.elem {
-            NodeElem::Tag { ref attrs, .. } => Some(attrs),
+    pub fn get_text_content(&self, id: NodeId) -> Option<&str> {
+        match self[id].elem {
+            NodeElem::Text { ref content, .. } => Some(content),
             _ => None,
         }
     }
 
-    pub fn get_parent(&self) -> Option<Rc<TreeNode>> {
-        match self.parent {
-            Some(ref x) => Some(x.upgrade().unwrap()),  // strong reference should alive, force unwrap it
+    pub fn is_tag(&self, id: NodeId) -> bool {
+        matches!(self[id].elem, NodeElem::Tag { .. })
+    }
+
+    pub fn get_tag_name(&self, id: NodeId) -> Option<&Arc<str>> {
+        match self[id].elem {
+            NodeElem::Tag { ref name, .. } => Some(name),
             _ => None,
         }
     }
 
-    pub fn get_childs(&self) -> Option<Vec<Rc<TreeNode>>> {
-        match self.elem {
-            NodeElem::Root { ref childs } => Some(childs.borrow().clone()),
-            NodeElem::Tag { ref childs, .. } => Some(childs.borrow().clone()),
+    pub fn get_tag_attrs(&self, id: NodeId) -> Option<&Attrs> {
+        match self[id].elem {
+            NodeElem::Tag { ref attrs, .. } => Some(attrs),
             _ => None,
         }
     }
 
-    // pub fn dbg_string(&self) -> String {
-    //     let id = self.id;
-    //     match self.elem {
-    //         NodeElem::Root { .. } => format!("[{}] TreeNode:Root", id),
-    //         NodeElem::Tag { ref name, ref attrs, .. } => format!("[{}] TreeNode:Tag(name: {}, attrs: {:?})", id, name, attrs),
-    //         NodeElem::Text { ref elem_type, ref content } => format!("[{}] TreeNode:Text(type: {}, content: {})", id, elem_type, content),
-    //     }
-    // }
-}
+    // Modify the element attributes, the start tag is rendered again if they change
+    pub fn update_tag_attrs<F: FnOnce(&mut Attrs)>(&mut self, id: NodeId, f: F) {
+        let modified = match self[id].elem {
+            NodeElem::Tag { ref mut attrs, .. } => {
+                let old = attrs.clone();
+                f(attrs);
+                *attrs != old
+            },
+            _ => false,
+        };
+        if let (true, Some(x)) = (modified, self[id].source.as_mut()) { x.modified = true; }
+    }
 
-fn _process_text_node(current: &Rc<TreeNode>, elem_type: &str, content: &str) {
-    let new_node = Rc::new(
-        TreeNode {
-            id: NODE_ID_NEXT.fetch_add(1, Ordering::Relaxed),
-            parent: Some(Rc::downgrade(current)),
-            elem: NodeElem::Text { elem_type: elem_type.to_owned(), content: content.to_owned() },
-        }
-    );
+    pub fn get_parent(&self, id: NodeId) -> Option<NodeId> {
+        self[id].parent
+    }
 
-    match current.elem {
-        NodeElem::Root { ref childs } => childs.borrow_mut().push(new_node),
-        NodeElem::Tag { ref childs, .. } => childs.borrow_mut().push(new_node),
-        NodeElem::Text { .. } => panic!("Cannot use `Text` node as parent"),
-    };
-}
+    pub fn get_childs(&self, id: NodeId) -> Childs<'_> {
+        Childs { tree: self, next: self[id].first_child }
+    }
 
-fn _process_start_tag(current: &Rc<TreeNode>, start_tag: &str, attrs: BTreeMap<String, Option<String>>) -> Rc<TreeNode> {
-    let mut working_node = current.clone();
+    // Next node in document order, without leaving the subtree of `scope`
+    pub fn next_in_scope(&self, id: NodeId, scope: NodeId) -> Option<NodeId> {
+        if let Some(child) = self[id].first_child { return Some(child); }
+        self.next_after_in_scope(id, scope)
+    }
 
-    // Autoclose optional HTML elements
-    if working_node.parent.is_some() {
-        if let Some(end_tag) = END.get(start_tag) {
-            working_node = _process_end_tag(&working_node, end_tag);
+    // Next node in document order after the descendants of `id`
+    pub fn next_after_in_scope(&self, id: NodeId, scope: NodeId) -> Option<NodeId> {
+        let mut node = id;
+        while node != scope {
+            if let Some(next) = self[node].next_sibling { return Some(next); }
+            node = self[node].parent?;
         }
-        else if let Some(x) = CLOSE.get(start_tag) {
-            let (ref allowed, ref scope) = *x;
+        None
+    }
 
-            // Close allowed parent elements in scope
-            let mut next = working_node.clone();
-            while next.parent.is_some() && !scope.contains(next.clone().get_tag_name().unwrap()) {
-                let this = next.clone();
-                let this_tag_name = this.get_tag_name().unwrap();
+    // Create a new node as the last child of `parent`
+    pub fn append(&mut self, parent: NodeId, elem: NodeElem) -> NodeId {
+        let id = self.create(elem);
+        self.insert_before(parent, None, id);
+        id
+    }
 
-                if allowed.contains(this_tag_name) {
-                    working_node = _process_end_tag(&working_node, this_tag_name);
-                }
+    // Link a detached node into `parent` before `before` (or as the last child)
+    pub fn insert_before(&mut self, parent: NodeId, before: Option<NodeId>, id: NodeId) {
+        if let NodeElem::Text { .. } = self[parent].elem { panic!("Cannot use `Text` node as parent"); }
 
-                next = next.get_parent().unwrap();
-            }
+        let prev = match before {
+            Some(x) => self[x].prev_sibling,
+            None => self[parent].last_child,
+        };
+
+        self[id].parent = Some(parent);
+        self[id].prev_sibling = prev;
+        self[id].next_sibling = before;
+
+        match prev {
+            Some(x) => self[x].next_sibling = Some(id),
+            None => self[parent].first_child = Some(id),
+        }
+        match before {
+            Some(x) => self[x].prev_sibling = Some(id),
+            None => self[parent].last_child = Some(id),
         }
     }
 
-    // New tag
-    let new_node = Rc::new(
-        TreeNode {
-            id: NODE_ID_NEXT.fetch_add(1, Ordering::Relaxed),
-            parent: Some(Rc::downgrade(&working_node)),
-            elem: NodeElem::Tag { name: start_tag.to_owned(), attrs: attrs, childs: RefCell::new(Vec::new()) },
+    // Unlink a node from its parent and siblings, the node keeps its own subtree
+    pub fn detach(&mut self, id: NodeId) {
+        let (parent, prev, next) = (self[id].parent, self[id].prev_sibling, self[id].next_sibling);
+
+        if let Some(parent) = parent {
+            match prev {
+                Some(x) => self[x].next_sibling = next,
+                None => self[parent].first_child = next,
+            }
+            match next {
+                Some(x) => self[x].prev_sibling = prev,
+                None => self[parent].last_child = prev,
+            }
         }
-    );
 
-    match working_node.elem {
-        NodeElem::Root { ref childs } => childs.borrow_mut().push(new_node.clone()),
-        NodeElem::Tag { ref childs, .. } => childs.borrow_mut().push(new_node.clone()),
-        NodeElem::Text { .. } => panic!("Cannot use `Text` node as parent"),
+        let node = &mut self[id];
+        node.parent = None;
+        node.prev_sibling = None;
+        node.next_sibling = None;
     }
 
-    new_node
-}
+    // Move all nodes of another tree into this one, returns the (detached) children of its root
+    pub fn adopt(&mut self, other: Tree) -> Vec<NodeId> {
+        self.collect();
+
+        // The root of the other tree is not moved
+        let ids: Vec<_> = (0 .. other.nodes.len()).map(|i| if i == 0 { other.root() } else { self.create(NodeElem::Root) }).collect();
+        let _map = |x: Option<NodeId>| x.map(|x| ids[x.0 as usize]);
 
-fn _process_end_tag(current: &Rc<TreeNode>, end_tag: &str) -> Rc<TreeNode> {
-    // Search stack for start tag
-    let mut next = current.clone();
-    while next.parent.is_some() {
-        let this = next.clone();
-        let this_tag_name = this.get_tag_name().unwrap();
+        let mut childs = Vec::new();
+        for (i, node) in other.nodes.into_iter().enumerate().skip(1) {
+            let id = ids[i];
+            let top = node.parent == Some(NodeId(0));
 
-        // Right tag
-        if this_tag_name == end_tag {
-            return next.get_parent().unwrap();
+            self[id].elem = node.elem;
+            self[id].first_child = _map(node.first_child);
+            self[id].last_child = _map(node.last_child);
+            if top {
+                childs.push(id);
+            } else {
+                self[id].parent = _map(node.parent);
+                self[id].prev_sibling = _map(node.prev_sibling);
+                self[id].next_sibling = _map(node.next_sibling);
+            }
         }
+        childs
+    }
+
+    // Deep copy of a node from another tree into the root of this one
+    pub fn copy_from(&mut self, other: &Tree, id: NodeId) {
+        let root = self.root();
+        self._copy_into(root, other, id);
+    }
 
-        // Phrasing content can only cross phrasing content
-        if PHRASING.contains(end_tag) && !PHRASING.contains(this_tag_name) {
-            return current.clone();
+    fn _copy_into(&mut self, parent: NodeId, other: &Tree, id: NodeId) {
+        let new_node = self.append(parent, other[id].elem.clone());
+        for child in other.get_childs(id) {
+            self._copy_into(new_node, other, child);
         }
+    }
+}
+
+// Nodes referred to by `DOM` objects
+#[derive(Debug, Default)]
+struct Pins {
+    map: HashMap<NodeId, Weak<()>>,
+    limit: usize, // size of the map before dropped pins are forgotten
+}
+
+pub struct Childs<'a> {
+    tree: &'a Tree,
+    next: Option<NodeId>,
+}
 
-        next = next.get_parent().unwrap();
+impl<'a> Iterator for Childs<'a> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        let current = self.next?;
+        self.next = self.tree[current].next_sibling;
+        Some(current)
     }
+}
 
-    // Ignore useless end tag
-    current.clone()
+/// The kind of a text node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TextKind {
+    /// A text.
+    Text,
+    /// A raw text of `script`, `style`, `title` and `textarea` elements.
+    Raw,
+    /// A comment.
+    Comment,
+    /// A CDATA section.
+    CData,
+    /// A document type declaration.
+    Doctype,
+    /// A processing instruction.
+    ProcessingInstruction,
 }
 
-pub fn parse(html: &str) -> Rc<TreeNode> {
-    let mut html = html;
+/// The type of a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeType {
+    /// The document root.
+    Root,
+    /// An element.
+    Tag,
+    /// A text (including raw text of `script` and `style` elements).
+    Text,
+    /// A comment.
+    Comment,
+    /// A CDATA section.
+    CData,
+    /// A document type declaration.
+    Doctype,
+    /// A processing instruction.
+    ProcessingInstruction,
+}
 
-    let root = Rc::new(
-        TreeNode {
-            id: NODE_ID_NEXT.fetch_add(1, Ordering::Relaxed),
-            parent: None,
-            elem: NodeElem::Root { childs: RefCell::new(Vec::new()) },
-        }
-    );
+/// The kind of a `ParseError`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// An element without end tag, which is not optional for this element.
+    UnclosedTag,
+    /// An end tag without a matching start tag.
+    StrayEndTag,
+    /// An attribute specified more than once on the same element.
+    DuplicateAttribute,
+    /// An unknown named or invalid numeric character reference.
+    BadEntity,
+}
 
-    let mut current = root.clone();
+/// An error returned by the strict HTML parser.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// The error kind.
+    pub kind: ParseErrorKind,
+    /// The line number of the offending token (starting at 1).
+    pub line: usize,
+    /// The column number of the offending token in characters (starting at 1).
+    pub column: usize,
+    /// The offending token.
+    pub token: String,
+}
 
-    lazy_static! {
-        static ref _TAG_PLUS_ATTRS_RE: Regex = Regex::new(r"^([^\s/]+)([\s\S]*)").unwrap();
+impl ParseError {
+    // Error for a slice of the source starting at `start`
+    fn new(kind: ParseErrorKind, start: Position, source: &str, token: &str) -> ParseError {
+        let before = &source[.. _offset(source, token)];
+        let (line, column) = match before.rfind('\n') {
+            Some(x) => (start.line + before.matches('\n').count(), before[x + 1 ..].chars().count() + 1),
+            None => (start.line, start.column + before.chars().count()),
+        };
+        ParseError { kind, line, column, token: token.to_owned() }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self.kind {
+            ParseErrorKind::UnclosedTag => "unclosed tag",
+            ParseErrorKind::StrayEndTag => "stray end tag",
+            ParseErrorKind::DuplicateAttribute => "duplicate attribute",
+            ParseErrorKind::BadEntity => "bad entity",
+        };
+        write!(f, "{} {:?} at line {}, column {}", reason, self.token, self.line, self.column)
     }
+}
 
-    let re = Regex::new(&*TOKEN_RE_STR).unwrap();
-    while let Some(caps) = re.captures(html) {
-        let text = caps.get(1);
-        let doctype = caps.get(2);
-        let comment = caps.get(3);
-        let cdata = caps.get(4);
-        let pi = caps.get(5);
-        let tag = caps.get(6);
-        let runaway = caps.get(11);
+impl Error for ParseError {}
+
+/// A position in the parsed source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    /// The byte offset (starting at 0).
+    pub offset: usize,
+    /// The line number (starting at 1).
+    pub line: usize,
+    /// The column number in characters (starting at 1).
+    pub column: usize,
+}
+
+/// A range of the parsed source, the end is exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    /// The first character.
+    pub start: Position,
+    /// The position after the last character.
+    pub end: Position,
+}
+
+/// The source ranges of a parsed node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeSpan {
+    /// The start tag of an element, or the whole node otherwise.
+    pub open: Span,
+    /// The end tag of an element, `None` if it has been omitted or the element is empty or self-closing.
+    pub close: Option<Span>,
+}
+
+// Tracks line and column numbers over consecutive tokens
+#[derive(Debug)]
+struct Locator {
+    position: Position,
+}
 
-        html = caps.get(12).map(|c| c.as_str()).unwrap_or(""); // html rest
+impl Locator {
+    fn new() -> Locator {
+        Locator { position: Position { offset: 0, line: 1, column: 1 } }
+    }
 
-        // Text (and runaway "<")
-        if let Some(text) = text {
-            if runaway.is_some() {
-                _process_text_node(&current, "text", &html_unescape(&(text.as_str().to_owned() + "<")));
+    fn span(&mut self, token: &Token) -> Span {
+        let start = Position { offset: token.offset, ..self.position };
+        for c in token.source.chars() {
+            if c == '\n' {
+                self.position.line += 1;
+                self.position.column = 1;
             } else {
-                _process_text_node(&current, "text", &html_unescape(text.as_str()));
+                self.position.column += 1;
             }
         }
+        self.position.offset = token.offset + token.source.len();
+        Span { start, end: self.position }
+    }
+}
 
-        // Tag
-        if let Some(tag) = tag {
-            // End: /tag
-            if tag.as_str().starts_with("/") {
-                let end_tag = tag.as_str().trim_start_matches('/').trim().to_lowercase();
-                current = _process_end_tag(&current, &end_tag);
-            }
-            // Start: tag
-            else {
-                let caps = _TAG_PLUS_ATTRS_RE.captures(tag.as_str()).unwrap(); // panic is ok
-                let mut start_tag = caps.get(1).unwrap().as_str().to_lowercase();
-                let attrs_str = caps.get(2).unwrap();
-
-                // Attributes
-                let mut attrs: BTreeMap<String, Option<String>> = BTreeMap::new();
-                let mut is_closing = false;
-                for caps in Regex::new(&*ATTR_RE_STR).unwrap().captures_iter(attrs_str.as_str()) {
-                    let key = caps.get(1).unwrap().as_str().to_owned().to_lowercase();
-                    let value = if caps.get(2).is_some() { caps.get(2) } else if caps.get(3).is_some() { caps.get(3) } else { caps.get(4) };
-
-                    // Empty tag
-                    if key == "/" {
-                        is_closing = true;
-                        continue;
-                    }
+fn _process_text_node(tree: &mut Tree, current: NodeId, kind: TextKind, content: &str, span: Span) -> NodeId {
+    let id = tree.append(current, NodeElem::Text { kind, content: content.to_owned() });
+    _process_span(tree, id, span, false);
+    id
+}
 
-                    attrs.insert(key, match value {
-                        Some(ref x) => Some(html_attr_unescape(x.as_str())),
-                        _ => None,
-                    });
-                }
+// Record the source of a start (or end) tag or another token
+fn _process_span(tree: &mut Tree, id: NodeId, span: Span, close: bool) {
+    let range = span.start.offset .. span.end.offset;
+    let node = &mut tree[id];
+    if close {
+        if let Some(ref mut x) = node.span { x.close = Some(span); }
+        if let Some(ref mut x) = node.source { x.close = Some(range); }
+    } else {
+        node.span = Some(NodeSpan { open: span, close: None });
+        node.source = Some(Source { open: range, close: None, modified: false });
+    }
+}
 
-                // "image" is an alias for "img"
-                if start_tag == "image" { start_tag = "img".to_owned() }
+// Keep the source of an ignored token with the previous token
+fn _process_ignored(tree: &mut Tree, (id, close): (NodeId, bool), span: Span) {
+    if let Some(ref mut x) = tree[id].source {
+        match x.close {
+            Some(ref mut range) if close => range.end = span.end.offset,
+            _ => x.open.end = span.end.offset,
+        }
+    }
+}
 
-                current = _process_start_tag(&current, &start_tag, attrs);
+// Elements opened by the parser (innermost last), closed by end tags or implicitly by the HTML rules
+#[derive(Debug)]
+pub struct OpenElements<T> {
+    items: Vec<(T, Arc<str>)>,
+    strict: bool,
+    xml: bool,
+}
 
-                // Element without end tag (self-closing)
-                if EMPTY.contains(start_tag.as_str()) || (!BLOCK.contains(start_tag.as_str()) && is_closing) {
-                    current = _process_end_tag(&current, &start_tag);
-                }
+impl<T> OpenElements<T> {
+    pub fn new(strict: bool, xml: bool) -> OpenElements<T> {
+        OpenElements { items: Vec::new(), strict, xml }
+    }
 
-                // Raw text elements
-                if RAW.contains(start_tag.as_str()) || RCDATA.contains(start_tag.as_str()) {
-                    let raw_text_re = Regex::new(&(r"(.+?)<\s*/\s*".to_owned() + &regex::escape(&start_tag) + r"\s*>(.*)$")).unwrap();
-                    if let Some(raw_text_caps) = raw_text_re.captures(html) {
-                        let raw_text = raw_text_caps.get(1).unwrap();
-                        html = raw_text_caps.get(2).map(|c| c.as_str()).unwrap_or("");
-
-                        if RCDATA.contains(&start_tag.as_str()) {
-                            _process_text_node(&current, "raw", &html_unescape(raw_text.as_str()))
-                        } else {
-                            _process_text_node(&current, "raw", raw_text.as_str())
-                        }
+    pub fn current(&self) -> Option<&T> {
+        self.items.last().map(|x| &x.0)
+    }
 
-                        current = _process_end_tag(&current, &start_tag);
-                    }
+    pub fn push(&mut self, item: T, name: Arc<str>) {
+        self.items.push((item, name));
+    }
+
+    // Close the elements implied by the start tag, returned from the outermost one
+    pub fn start(&mut self, start_tag: &str) -> Vec<(T, Arc<str>)> {
+        let mut depth = self.items.len();
+
+        // Autoclose optional HTML elements
+        if !self.xml && depth > 0 {
+            if let Some(end_tag) = END.get(start_tag) {
+                depth = self._depth(depth, end_tag).unwrap_or(depth);
+            }
+            else if let Some(x) = CLOSE.get(start_tag) {
+                let (ref allowed, ref scope) = *x;
+
+                // Close allowed parent elements in scope
+                for i in (0 .. self.items.len()).rev() {
+                    let name = &*self.items[i].1;
+                    if scope.contains(name) { break; }
+                    if allowed.contains(name) { depth = self._depth(depth, name).unwrap_or(depth); }
                 }
             }
         }
 
-        // DOCTYPE
-        else if let Some(doctype) = doctype {
-            _process_text_node(&current, "doctype", doctype.as_str());
+        self.items.split_off(depth)
+    }
+
+    // Close the element with the end tag and the elements it contains, returned from the outermost one
+    // (`None` for a useless end tag)
+    pub fn end(&mut self, end_tag: &str) -> Option<Vec<(T, Arc<str>)>> {
+        let depth = self._depth(self.items.len(), end_tag)?;
+        Some(self.items.split_off(depth))
+    }
+
+    // Close all elements at the end of the document
+    pub fn finish(&mut self) -> Vec<(T, Arc<str>)> {
+        self.items.split_off(0)
+    }
+
+    // Number of elements left open after closing the element with the end tag from the first `depth` elements
+    fn _depth(&self, depth: usize, end_tag: &str) -> Option<usize> {
+        // Search stack for start tag
+        for i in (0 .. depth).rev() {
+            let name = &*self.items[i].1;
+
+            // Right tag
+            if name == end_tag { return Some(i); }
+
+            // Phrasing content can only cross phrasing content (and elements closed implicitly in strict mode)
+            let crossed = PHRASING.contains(name) || (self.strict && OPTIONAL.contains(name));
+            if !self.xml && PHRASING.contains(end_tag) && !crossed { return None; }
         }
 
-        // Comment
-        else if let Some(comment) = comment {
-            _process_text_node(&current, "comment", comment.as_str());
+        // Ignore useless end tag
+        None
+    }
+}
+
+// Tag name and attributes of a start tag
+pub fn start_tag(name: &str, attrs: &[(&str, Option<&str>)], xml: bool) -> (String, Attrs) {
+    let mut name = tag_name(name, xml);
+
+    // "image" is an alias for "img"
+    if !xml && name == "image" { name = "img".to_owned() }
+
+    let mut result = Attrs::new();
+    for &(key, value) in attrs {
+        result.parsed(tag_name(key, xml), value.map(html_attr_unescape));
+    }
+    (name, result)
+}
+
+// Element without end tag (self-closing)
+pub fn is_closed_by_start_tag(name: &str, self_closing: bool, xml: bool) -> bool {
+    (!xml && EMPTY.contains(name)) || ((xml || !BLOCK.contains(name)) && self_closing)
+}
+
+// Documents starting with an XML declaration are parsed in XML mode
+pub fn detect_xml(html: &str) -> bool {
+    lazy_static! {
+        static ref _XML_DECL_RE: Regex = Regex::new(r"^(?i)\s*<\?xml[\s?]").unwrap();
+    }
+    _XML_DECL_RE.is_match(html)
+}
+
+// Input of an incremental parser buffered until XML mode can be detected
+#[derive(Debug, Default)]
+pub struct PendingInput(String);
+
+impl PendingInput {
+    // Tokenizer in the detected mode with all buffered input, once the input is long enough
+    // to detect an XML declaration ("<?xml" and one more character) or complete
+    pub fn push(&mut self, chunk: &str, finish: bool) -> Option<TokenBuffer> {
+        self.0.push_str(chunk);
+        if !finish && self.0.trim_start().len() < 6 { return None; }
+        let mut tokens = TokenBuffer::new(detect_xml(&self.0));
+        tokens.push(&mem::take(&mut self.0));
+        Some(tokens)
+    }
+}
+
+pub fn parse(html: &str, xml: bool) -> Tree {
+    _parse(html, false, xml).unwrap() // lenient parser never fails
+}
+
+// Keep the source to render unmodified nodes as is
+pub fn parse_lossless(html: &str, xml: bool) -> Tree {
+    let mut tree = parse(html, xml);
+    tree.source = Some(Arc::from(html));
+    tree
+}
+
+pub fn parse_strict(html: &str, xml: bool) -> Result<Tree, ParseError> {
+    _parse(html, true, xml)
+}
+
+fn _parse(html: &str, strict: bool, xml: bool) -> Result<Tree, ParseError> {
+    let mut builder = TreeBuilder::new(strict, xml);
+    let tokenizer = if xml { Tokenizer::new_xml(html) } else { Tokenizer::new(html) };
+    for token in tokenizer { builder.token(token)?; }
+    builder.finish()
+}
+
+// Builds the tree from consecutive tokens, which can come from the whole input or from chunks
+#[derive(Debug)]
+pub struct TreeBuilder {
+    tree: Tree,
+    open: OpenElements<NodeId>,
+    locator: Locator,
+    last: (NodeId, bool), // the node of the previous token (and if it has been the end tag)
+    rcdata: bool, // raw text of the last start tag contains entities
+    strict: bool,
+    open_tags: HashMap<NodeId, String>, // sources of open tags (strict mode only)
+}
+
+impl TreeBuilder {
+    pub fn new(strict: bool, xml: bool) -> TreeBuilder {
+        let mut tree = Tree::new();
+        tree.xml = xml;
+        let root = tree.root();
+        tree[root].source = Some(Source { open: 0 .. 0, close: None, modified: false });
+        TreeBuilder {
+            tree,
+            open: OpenElements::new(strict, xml),
+            locator: Locator::new(),
+            last: (root, false),
+            rcdata: false,
+            strict,
+            open_tags: HashMap::new(),
         }
+    }
 
-        // CDATA
-        else if let Some(cdata) = cdata {
-            _process_text_node(&current, "cdata", cdata.as_str());
+    pub fn token(&mut self, token: Token) -> Result<(), ParseError> {
+        let TreeBuilder { ref mut tree, ref mut open, ref mut last, ref mut rcdata, strict, ref mut open_tags, .. } = *self;
+        let xml = tree.xml;
+        let root = tree.root();
+        let current = open.current().cloned().unwrap_or(root);
+        let span = self.locator.span(&token);
+        let error = |kind, slice| ParseError::new(kind, span.start, token.source, slice);
+        let check_entities = |text, is_attr| match find_bad_entity(text, is_attr) {
+            Some(entity) => Err(error(ParseErrorKind::BadEntity, entity)),
+            None => Ok(()),
+        };
+
+        match token.kind {
+            // Text
+            TokenKind::Text(text) => {
+                if strict { check_entities(text, false)?; }
+                *last = (_process_text_node(tree, current, TextKind::Text, &html_unescape(text), span), false);
+            },
+
+            // Raw text
+            TokenKind::RawText(text) => {
+                if *rcdata {
+                    if strict { check_entities(text, false)?; }
+                    *last = (_process_text_node(tree, current, TextKind::Raw, &html_unescape(text), span), false);
+                } else {
+                    *last = (_process_text_node(tree, current, TextKind::Raw, text, span), false);
+                }
+            },
+
+            // End tag
+            TokenKind::EndTag(end_tag) => {
+                match open.end(&tag_name(end_tag, xml)) {
+                    Some(closed) => {
+                        // The element with the end tag is the outermost one
+                        if strict { _check_closed(tree, &closed[1 ..], open_tags)?; }
+                        _process_span(tree, closed[0].0, span, true);
+                        *last = (closed[0].0, true);
+                    },
+                    None if strict => return Err(error(ParseErrorKind::StrayEndTag, token.source)),
+                    None => _process_ignored(tree, *last, span),
+                }
+            },
+
+            // Start tag
+            TokenKind::StartTag { name, ref attrs, self_closing } => {
+                let (start_tag, attrs_map) = start_tag(name, attrs, xml);
+                if strict {
+                    for (i, &(key, value)) in attrs.iter().enumerate() {
+                        if attrs[.. i].iter().any(|x| tag_name(x.0, xml) == tag_name(key, xml)) {
+                            return Err(error(ParseErrorKind::DuplicateAttribute, key));
+                        }
+                        if let Some(value) = value { check_entities(value, true)?; }
+                    }
+                }
+                *rcdata = RCDATA.contains(start_tag.as_str());
+
+                // New tag (closing elements implicitly)
+                let closed = open.start(&start_tag);
+                if strict { _check_closed(tree, &closed, open_tags)?; }
+                let parent = open.current().cloned().unwrap_or(root);
+                let name: Arc<str> = Arc::from(start_tag);
+                let element = tree.append(parent, NodeElem::Tag { name: name.clone(), attrs: attrs_map });
+                _process_span(tree, element, span, false);
+                *last = (element, false);
+                if strict { open_tags.insert(element, token.source.to_owned()); }
+
+                open.push(element, name.clone());
+                if is_closed_by_start_tag(&name, self_closing, xml) { open.end(&name); }
+            },
+
+            // DOCTYPE
+            TokenKind::Doctype(doctype) => {
+                *last = (_process_text_node(tree, current, TextKind::Doctype, doctype, span), false);
+            },
+
+            // Comment
+            TokenKind::Comment(comment) => {
+                *last = (_process_text_node(tree, current, TextKind::Comment, comment, span), false);
+            },
+
+            // CDATA
+            TokenKind::CData(cdata) => {
+                *last = (_process_text_node(tree, current, TextKind::CData, cdata, span), false);
+            },
+
+            // Processing instruction
+            TokenKind::ProcessingInstruction(pi) => {
+                *last = (_process_text_node(tree, current, TextKind::ProcessingInstruction, pi, span), false);
+            },
         }
+        Ok(())
+    }
 
-        // Processing instruction
-        else if let Some(pi) = pi {
-            _process_text_node(&current, "pi", pi.as_str());
+    pub fn finish(mut self) -> Result<Tree, ParseError> {
+        if self.strict { _check_closed(&self.tree, &self.open.finish(), &self.open_tags)?; }
+        Ok(self.tree)
+    }
+}
+
+// Tag and attribute names are case-insensitive in HTML
+pub fn tag_name(name: &str, xml: bool) -> String {
+    if xml { name.to_owned() } else { name.to_lowercase() }
+}
+
+fn _offset(original: &str, slice: &str) -> usize {
+    slice.as_ptr() as usize - original.as_ptr() as usize
+}
+
+// Elements closed without end tags, which must be optional
+fn _check_closed(tree: &Tree, closed: &[(NodeId, Arc<str>)], open_tags: &HashMap<NodeId, String>) -> Result<(), ParseError> {
+    for &(node, ref name) in closed.iter().rev() {
+        if tree.xml || !OPTIONAL.contains(&**name) {
+            let source = &open_tags[&node];
+            return Err(ParseError::new(ParseErrorKind::UnclosedTag, tree[node].span.unwrap().open.start, source, source));
         }
+    }
+    Ok(())
+}
+
+/// Options for `DOM::render`.
+///
+/// The default options render HTML the same way as `to_string`.
+///
+/// ```
+/// use victoria_dom::{DOM, RenderOptions, AttrOrder, Escape, Quote};
+/// let dom = DOM::new("<ul><li title='say \"hi\"' id=a>A &amp; B</li><li>C</li></ul>");
+/// let options = RenderOptions {
+///     attr_order: AttrOrder::Source,
+///     escape: Escape::Minimal,
+///     quote: Quote::Auto,
+///     omit_optional_end_tags: true,
+///     ..RenderOptions::default()
+/// };
+/// assert_eq!(dom.render(&options), "<ul><li title='say \"hi\"' id=\"a\">A &amp; B<li>C</ul>");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderOptions {
+    /// Render XML instead of HTML, `None` for the mode the document has been parsed in.
+    pub xml: Option<bool>,
+    /// Pretty-print with the indent per nesting level (see `DOM::to_pretty_string`).
+    pub indent: Option<String>,
+    /// Attribute order.
+    pub attr_order: AttrOrder,
+    /// Characters to escape in text and attribute values.
+    pub escape: Escape,
+    /// Character references for escaped characters.
+    pub entities: Entities,
+    /// Quotes around attribute values.
+    pub quote: Quote,
+    /// Omit end tags that are implied by the following element or the end of the parent element (HTML only).
+    pub omit_optional_end_tags: bool,
+    /// Render attributes without value as `checked="checked"` instead of `checked` (always the case for XML).
+    pub expand_boolean_attrs: bool,
+    /// Render unmodified nodes of documents parsed with `DOM::new_lossless` as they have been in the source
+    /// (unless the output is pretty-printed or in another mode than the document).
+    pub lossless: bool,
+}
 
-        if html.is_empty() { break; }
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        RenderOptions {
+            xml: None,
+            indent: None,
+            attr_order: AttrOrder::Alphabetical,
+            escape: Escape::Full,
+            entities: Entities::Named,
+            quote: Quote::Double,
+            omit_optional_end_tags: false,
+            expand_boolean_attrs: false,
+            lossless: true,
+        }
     }
+}
 
-    root
+/// The attribute order for `RenderOptions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttrOrder {
+    /// Sorted by name.
+    Alphabetical,
+    /// As in the source document (new attributes are added to the end).
+    Source,
 }
 
-pub fn render (root: &Rc<TreeNode>) -> String {
-    match root.elem {
-        // Text (escaped)
-        NodeElem::Text { ref elem_type, ref content } if elem_type == "text" => {
-            return xml_escape(content)
-        },
+/// The escaping level for `RenderOptions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Escape {
+    /// Escape `&`, `<`, `>`, `"` and `'` everywhere.
+    Full,
+    /// Escape only `&`, `<` and `>` in text and `&` and the quote character in attribute values.
+    Minimal,
+}
 
-        // Raw text
-        NodeElem::Text { ref elem_type, ref content } if elem_type == "raw" => {
-            return content.clone()
-        },
+/// The character reference style for `RenderOptions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entities {
+    /// Named references like `&amp;` (`'` has no HTML 4 name and is always `&#39;`).
+    Named,
+    /// Numeric references like `&#38;`.
+    Numeric,
+}
 
-        // DOCTYPE
-        NodeElem::Text { ref elem_type, ref content } if elem_type == "doctype" => {
-            return "<!DOCTYPE".to_owned() + content + ">"
-        },
+/// The attribute value quotes for `RenderOptions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quote {
+    /// Always `"`.
+    Double,
+    /// Always `'`.
+    Single,
+    /// `'` for values with `"` but without `'`, otherwise `"`.
+    Auto,
+}
 
-        // Comment
-        NodeElem::Text { ref elem_type, ref content } if elem_type == "comment" => {
-            return "<!--".to_owned() + content + "-->"
-        },
+pub fn render(tree: &Tree, id: NodeId, options: &RenderOptions) -> String {
+    let renderer = Renderer::new(tree, options);
+    let mut result = String::new();
+    match options.indent {
+        Some(ref indent) => renderer.pretty(id, indent, &mut result),
+        None => renderer.node(id, &mut result),
+    }
+    result
+}
 
-        // CDATA
-        NodeElem::Text { ref elem_type, ref content } if elem_type == "cdata" => {
-            return "<![CDATA[".to_owned() + content + "]]>"
-        },
+// Render the content of an element
+pub fn render_content(tree: &Tree, id: NodeId, options: &RenderOptions) -> String {
+    let renderer = Renderer::new(tree, options);
+    let mut result = String::new();
+    renderer.content(id, &mut result);
+    result
+}
 
-        // Processing instruction
-        NodeElem::Text { ref elem_type, ref content } if elem_type == "pi" => {
-            return "<?".to_owned() + content + "?>"
-        },
+struct Renderer<'a> {
+    tree: &'a Tree,
+    options: &'a RenderOptions,
+    xml: bool,
+    source: Option<&'a str>,
+}
 
-        // Root
-        NodeElem::Root { ref childs } => {
-            return childs.borrow().iter().map(|ref x| { render(x) }).collect::<Vec<String>>().concat();
-        },
+impl<'a> Renderer<'a> {
+    fn new(tree: &'a Tree, options: &'a RenderOptions) -> Renderer<'a> {
+        let xml = options.xml.unwrap_or(tree.xml);
+        let source = match tree.source {
+            Some(ref x) if options.lossless && options.indent.is_none() && xml == tree.xml => Some(&**x),
+            _ => None,
+        };
+        Renderer { tree, options, xml, source }
+    }
+
+    fn node(&self, id: NodeId, result: &mut String) {
+        if self.lossless(id, result) { return; }
+
+        let tree = self.tree;
+        match tree[id].elem {
+            // Text (escaped)
+            NodeElem::Text { kind: TextKind::Text, ref content } => {
+                *result += &self.escape(content, None);
+            },
+
+            // Raw text (as CDATA section in XML, if it contains markup)
+            NodeElem::Text { kind: TextKind::Raw, ref content } => {
+                if self.xml && content.contains(['<', '&']) {
+                    *result += &("<![CDATA[".to_owned() + &content.replace("]]>", "]]]]><![CDATA[>") + "]]>");
+                } else {
+                    result.push_str(content);
+                }
+            },
+
+            // DOCTYPE
+            NodeElem::Text { kind: TextKind::Doctype, ref content } => {
+                *result += &("<!DOCTYPE".to_owned() + content + ">");
+            },
+
+            // Comment
+            NodeElem::Text { kind: TextKind::Comment, ref content } => {
+                *result += &("<!--".to_owned() + content + "-->");
+            },
+
+            // CDATA
+            NodeElem::Text { kind: TextKind::CData, ref content } => {
+                *result += &("<![CDATA[".to_owned() + content + "]]>");
+            },
+
+            // Processing instruction
+            NodeElem::Text { kind: TextKind::ProcessingInstruction, ref content } => {
+                *result += &("<?".to_owned() + content + "?>");
+            },
+
+            // Root
+            NodeElem::Root => {
+                for child in tree.get_childs(id) { self.node(child, result); }
+            },
+
+            NodeElem::Tag { ref name, .. } => {
+                self.start_tag(id, result);
+
+                // No children
+                if tree[id].first_child.is_none() {
+                    if self.xml {
+                        *result += "/>";
+                    } else {
+                        *result += ">";
+                        if !EMPTY.contains(&**name) { *result += &self.end_tag(id); }
+                    }
+                    return;
+                }
+
+                // Children
+                *result += ">";
+                for child in tree.get_childs(id) { self.node(child, result); }
+                *result += &self.end_tag(id);
+            },
+        }
+    }
 
-        NodeElem::Tag { ref name, ref attrs, ref childs } => {
-            let mut result = "<".to_owned() + name;
+    fn content(&self, id: NodeId, result: &mut String) {
+        let tree = self.tree;
 
-            // Attributes
-            for (key, value) in attrs.iter() {
-                match *value {
-                    Some(ref x) => { result = result + " " + key + "=\"" + &xml_escape(x) + "\"" },
-                    None        => { result = result + " " + key },
+        // Ignored tokens after the start tag
+        if let (Some(source), Some(range)) = (self.source, &tree[id].source) {
+            let start = tree[id].span.map_or(range.open.start, |x| x.open.end.offset);
+            result.push_str(&source[start .. range.open.end]);
+        }
+
+        for child in tree.get_childs(id) { self.node(child, result); }
+    }
+
+    // Render the node with the source of its tokens, if it has not been modified
+    fn lossless(&self, id: NodeId, result: &mut String) -> bool {
+        let tree = self.tree;
+        let (source, range) = match (self.source, &tree[id].source) {
+            (Some(source), Some(range)) => (source, range),
+            _ => return false,
+        };
+
+        let name = match (&tree[id].elem, tree[id].span) {
+            (NodeElem::Tag { name, .. }, Some(span)) => {
+                let start_tag = &source[range.open.start .. span.open.end.offset];
+
+                // Self-closing tag with new content (parsed children have a source, even after "<p/>" in HTML)
+                if start_tag.ends_with("/>") && tree.get_childs(id).any(|x| tree[x].source.is_none()) { return false; }
+
+                if range.modified {
+                    self.lossless_start_tag(id, start_tag, result);
+                    *result += if start_tag.ends_with("/>") { "/>" } else { ">" };
+                } else {
+                    result.push_str(start_tag);
                 }
+                name
+            },
+            (NodeElem::Root, _) => {
+                self.content(id, result);
+                return true;
+            },
+            _ => {
+                result.push_str(&source[range.open.clone()]);
+                return true;
+            },
+        };
+
+        self.content(id, result);
+        match range.close {
+            Some(ref x) => result.push_str(&source[x.clone()]),
+
+            // Omitted end tag, which might be required now
+            None if tree[id].first_child.is_some() && tree[id].next_sibling.is_some() && !self._is_end_tag_implied(id) => {
+                *result += &("</".to_owned() + name + ">");
+            },
+            None => {},
+        }
+        true
+    }
+
+    // Start tag without the closing ">"
+    fn start_tag(&self, id: NodeId, result: &mut String) {
+        let (name, attrs) = match self.tree[id].elem {
+            NodeElem::Tag { ref name, ref attrs } => (name, attrs),
+            _ => return,
+        };
+
+        *result += "<";
+        *result += name;
+
+        // Attributes
+        let mut attrs: Vec<_> = attrs.iter().collect();
+        if self.options.attr_order == AttrOrder::Alphabetical { attrs.sort(); }
+        for (key, value) in attrs { self.attr(key, value, result); }
+    }
+
+    // Start tag with modified attributes (without the closing ">"), keeping the source of the name
+    // and the unchanged attributes, all in stored order
+    fn lossless_start_tag(&self, id: NodeId, start_tag: &str, result: &mut String) {
+        let attrs = match self.tree[id].elem {
+            NodeElem::Tag { ref attrs, .. } => attrs,
+            _ => return,
+        };
+        let (name, source_attrs) = match Tokenizer::new(start_tag).next().map(|x| x.kind) {
+            Some(TokenKind::StartTag { name, attrs, .. }) => (name, attrs),
+            _ => return self.start_tag(id, result),
+        };
+
+        *result += "<";
+        *result += name;
+        for (key, value) in attrs.iter() {
+            // The last occurrence in the source has the value
+            let unchanged = source_attrs.iter().rev().find(|x| tag_name(x.0, self.xml) == *key)
+                .filter(|x| x.1.map(html_attr_unescape) == *value);
+            match unchanged {
+                Some(&(key, value)) => *result += &(" ".to_owned() + _attr_source(start_tag, key, value)),
+                None => self.attr(key, value, result),
             }
+        }
+    }
+
+    fn attr(&self, key: &str, value: &Option<String>, result: &mut String) {
+        let value = match *value {
+            Some(ref x) => x,
+            None if self.xml || self.options.expand_boolean_attrs => key,
+            None => { *result += &(" ".to_owned() + key); return; },
+        };
+
+        let quote = match self.options.quote {
+            Quote::Double => '"',
+            Quote::Single => '\'',
+            Quote::Auto => if value.contains('"') && !value.contains('\'') { '\'' } else { '"' },
+        };
+        *result += &format!(" {}={}{}{}", key, quote, self.escape(value, Some(quote)), quote);
+    }
+
+    fn end_tag(&self, id: NodeId) -> String {
+        if self.options.omit_optional_end_tags && !self.xml && self._is_end_tag_implied(id) { return String::new(); }
+        "</".to_owned() + self.tree.get_tag_name(id).unwrap() + ">"
+    }
 
-            // No children
-            if childs.borrow().is_empty() {
-                return if EMPTY.contains(&name[..]) { result + ">" } else { result + "></" + name + ">" };
+    // The parser closes the element by the start tag of the next sibling element or the end tag of the parent element
+    fn _is_end_tag_implied(&self, id: NodeId) -> bool {
+        let tree = self.tree;
+        let name = &**tree.get_tag_name(id).unwrap();
+        if !OPTIONAL.contains(name) { return false; }
+
+        match tree[id].next_sibling {
+            Some(next) => match tree.get_tag_name(next) {
+                Some(next) => END.get(&**next) == Some(&name) || CLOSE.get(&**next).is_some_and(|x| x.0.contains(name)),
+                None => false, // text would end up in the element
+            },
+            None => match tree.get_parent(id).and_then(|x| tree.get_tag_name(x)) {
+                Some(parent) => !PHRASING.contains(&**parent), // phrasing end tags can't cross other elements
+                None => true,
+            },
+        }
+    }
+
+    // Escape text (or an attribute value with the quote)
+    fn escape(&self, text: &str, quote: Option<char>) -> String {
+        let _is_escaped = |c: char| match self.options.escape {
+            Escape::Full => matches!(c, '&' | '<' | '>' | '"' | '\''),
+            Escape::Minimal => c == '&' || Some(c) == quote || (quote.is_none() && (c == '<' || c == '>')),
+        };
+
+        xml_escape(text, _is_escaped, self.options.entities == Entities::Numeric)
+    }
+
+    // Render with every block level element on its own line, inline content is kept on one line
+    // and whitespace sensitive elements are rendered as is
+    fn pretty(&self, id: NodeId, indent: &str, result: &mut String) {
+        match self.tree[id].elem {
+            NodeElem::Root => self.pretty_nodes(self.tree.get_childs(id), indent, 0, result),
+            _ => self.pretty_nodes(Some(id).into_iter(), indent, 0, result),
+        }
+    }
+
+    fn pretty_nodes<I: Iterator<Item = NodeId>>(&self, nodes: I, indent: &str, depth: usize, result: &mut String) {
+        // Consecutive inline nodes share a line
+        let mut line = String::new();
+        for node in nodes {
+            if self.is_inline(node) {
+                self.node(node, &mut line);
+                continue;
             }
 
-            // Children
-            return
-                result + ">" +
-                &childs.borrow().iter().map(|ref x| { render(x) }).collect::<Vec<String>>().concat() +
-                "</" + name + ">";
-        },
+            _push_line(&line, indent, depth, result);
+            line.clear();
+            self.pretty_block(node, indent, depth, result);
+        }
+        _push_line(&line, indent, depth, result);
+    }
+
+    fn pretty_block(&self, id: NodeId, indent: &str, depth: usize, result: &mut String) {
+        let tree = self.tree;
+        let name = match tree.get_tag_name(id) {
+            Some(x) => x,
+            None => {
+                let mut line = String::new();
+                self.node(id, &mut line);
+                return _push_line(&line, indent, depth, result);
+            },
+        };
+
+        // Whitespace is significant in preformatted and raw text elements (and in XML elements with text)
+        let preformatted = if self.xml {
+            tree.get_childs(id).any(|x| tree.get_text_content(x).is_some_and(|x| !_trim(x).is_empty()))
+        } else {
+            &**name == "pre" || RAW.contains(&**name) || RCDATA.contains(&**name)
+        };
+
+        if preformatted || tree[id].first_child.is_none() {
+            let mut line = String::new();
+            self.node(id, &mut line);
+            return _push_line(&line, indent, depth, result);
+        }
 
-        _ => { return "".to_owned() },
+        let mut start_tag = String::new();
+        self.start_tag(id, &mut start_tag);
+        start_tag += ">";
+        let end_tag = self.end_tag(id);
+
+        // Inline content only
+        if tree.get_childs(id).all(|x| self.is_inline(x)) {
+            let mut content = String::new();
+            for child in tree.get_childs(id) { self.node(child, &mut content); }
+            return _push_line(&(start_tag + _trim(&content) + &end_tag), indent, depth, result);
+        }
+
+        _push_line(&start_tag, indent, depth, result);
+        self.pretty_nodes(tree.get_childs(id), indent, depth + 1, result);
+        _push_line(&end_tag, indent, depth, result);
+    }
+
+    fn is_inline(&self, id: NodeId) -> bool {
+        match self.tree[id].elem {
+            NodeElem::Text { kind, .. } => kind == TextKind::Text || kind == TextKind::Raw || kind == TextKind::CData,
+            NodeElem::Tag { ref name, .. } => !self.xml && PHRASING.contains(&**name),
+            NodeElem::Root => false,
+        }
     }
 }
+
+fn _push_line(line: &str, indent: &str, depth: usize, result: &mut String) {
+    let line = _trim(line);
+    if line.is_empty() { return; }
+
+    for _ in 0 .. depth { result.push_str(indent); }
+    result.push_str(line);
+    result.push('\n');
+}
+
+// Trim HTML whitespace only (non-breaking spaces are content)
+fn _trim(text: &str) -> &str {
+    text.trim_matches(|c| matches!(c, ' ' | '\t' | '\n' | '\r' | '\x0C'))
+}
+
+// Source of an attribute of a start tag, from the name to the end of the (quoted) value
+fn _attr_source<'a>(start_tag: &'a str, key: &str, value: Option<&str>) -> &'a str {
+    let start = _offset(start_tag, key);
+    let end = match value {
+        Some(value) => {
+            let (value_start, value_end) = (_offset(start_tag, value), _offset(start_tag, value) + value.len());
+            let quote = start_tag[value_end ..].chars().next().filter(|&x| x == '"' || x == '\'');
+            if quote.is_some() && start_tag[.. value_start].chars().last() == quote { value_end + 1 } else { value_end }
+        },
+        None => start + key.len(),
+    };
+    &start_tag[start .. end]
+}
@@ -1,18 +1,45 @@
 mod css;
+mod encoding;
+mod events;
 mod html;
+mod parser;
+mod tokenizer;
 
-use std::collections::BTreeMap;
-use std::rc::Rc;
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 use regex::Regex;
 
-use self::html::TreeNode;
+use self::html::{Tree, NodeId, NodeElem};
+
+pub use self::css::{Selector, SelectorError, SelectorErrorKind};
+pub use self::events::{Event, EventParser, EventReader};
+pub use self::html::{AttrOrder, Attrs, Entities, Escape, NodeSpan, NodeType, ParseError, ParseErrorKind, Position, Quote};
+pub use self::html::{RenderOptions, Span, TextKind};
+pub use self::parser::Parser;
+pub use self::tokenizer::{Token, TokenKind, Tokenizer};
 
 /// The HTML `DOM` type
+///
+/// `DOM` objects are `Send + Sync`, so documents can be parsed on one thread and queried on another.
+/// All `DOM` objects of a document share a single lock, every method call sees the document
+/// in a consistent state and mutations are applied atomically.
+/// Removed elements stay usable while `DOM` objects refer to them, afterwards their memory is reused for new nodes.
+///
+/// ```
+/// use victoria_dom::DOM;
+/// use std::thread;
+///
+/// let dom = thread::spawn(|| DOM::new("<p>Hello</p>")).join().unwrap();
+/// assert_eq!(dom.at("p").unwrap().text(), "Hello");
+/// ```
 #[derive(Debug)]
 pub struct DOM {
-    root: Rc<TreeNode>, // To avoid destroying the root node ahead of time
-    tree: Rc<TreeNode>,
+    tree: Arc<RwLock<Tree>>,
+    id: NodeId,
+    name: Option<Arc<str>>, // Tag names never change, so they are cached for `tag()`
+    _pin: Arc<()>, // Keeps the node in the arena after it has been removed from the document
 }
 
 impl DOM {
@@ -23,8 +50,140 @@ impl DOM {
     /// let dom = DOM::new("<div id=\"title\">Hello</div>");
     /// ```
     pub fn new(html: &str) -> DOM {
-        let tree = html::parse(html);
-        DOM { root: tree.clone(), tree: tree }
+        DOM::_new(html::parse(html, html::detect_xml(html)))
+    }
+
+    /// Construct a new `DOM` object and parse HTML (or XML like `new`), keeping the source
+    /// to render unmodified nodes exactly as they have been.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let html = "<UL>\n  <li class=x>Fish &amp chips&nbsp;!\n  <li>Pie</UL>";
+    /// let dom = DOM::new_lossless(html);
+    /// assert_eq!(dom.to_string(), html);
+    ///
+    /// dom.find("li")[1].set_attr("class", "y");
+    /// assert_eq!(dom.to_string(), "<UL>\n  <li class=x>Fish &amp chips&nbsp;!\n  <li class=\"y\">Pie</UL>");
+    /// ```
+    pub fn new_lossless(html: &str) -> DOM {
+        DOM::_new(html::parse_lossless(html, html::detect_xml(html)))
+    }
+
+    /// Construct a new `DOM` object and parse XML.
+    ///
+    /// In XML mode tag and attribute names keep their case, every `<tag/>` is self-closing
+    /// and no HTML specific rules (like implicitly closed elements) are applied.
+    /// Documents starting with an XML declaration are parsed in XML mode by `new` as well.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new_xml("<feed><entry><Title>A</Title><link href=\"/a\"/></entry><p><p/></p></feed>");
+    /// assert_eq!(dom.at("entry > Title").unwrap().text(), "A");
+    /// assert_eq!(dom.find("p p").len(), 1);
+    /// assert!(DOM::new("<?xml version=\"1.0\"?><rss/>").is_xml());
+    /// ```
+    pub fn new_xml(xml: &str) -> DOM {
+        DOM::_new(html::parse(xml, true))
+    }
+
+    /// Construct a new `DOM` object and parse HTML, rejecting broken markup instead of repairing it.
+    ///
+    /// ```
+    /// use victoria_dom::{DOM, ParseErrorKind};
+    /// assert!(DOM::parse_strict("<p>Hello<br></p>").is_ok());
+    ///
+    /// let err = DOM::parse_strict("<div>\n  <b>Hello</div>").unwrap_err();
+    /// assert_eq!(err.kind, ParseErrorKind::UnclosedTag);
+    /// assert_eq!((err.line, err.column, err.token.as_str()), (2, 3, "<b>"));
+    /// ```
+    pub fn parse_strict(html: &str) -> Result<DOM, ParseError> {
+        Ok(DOM::_new(html::parse_strict(html, html::detect_xml(html))?))
+    }
+
+    /// Construct a new `DOM` object and parse HTML (or XML like `new`) from a reader, see `Parser`.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::from_reader("<p>Hello".as_bytes()).unwrap();
+    /// assert_eq!(dom.to_string(), "<p>Hello</p>");
+    /// ```
+    pub fn from_reader<R: Read>(mut reader: R) -> io::Result<DOM> {
+        let mut parser = Parser::new();
+        let mut chunk = [0; 8192];
+        loop {
+            match reader.read(&mut chunk) {
+                Ok(0) => return Ok(parser.finish()),
+                Ok(len) => parser.feed(&chunk[.. len]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Construct a new `DOM` object and parse HTML (or XML like `new`) in any encoding.
+    ///
+    /// The encoding is detected from a byte order mark, a `<meta charset>` or
+    /// `<meta http-equiv="Content-Type">` element in the first 1024 bytes, and defaults
+    /// to UTF-8 (or windows-1252 for invalid UTF-8).
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::from_bytes(b"<meta charset=\"windows-1251\"><p>\xcf\xf0\xe8\xe2\xe5\xf2</p>");
+    /// assert_eq!(dom.at("p").unwrap().text(), "Привет");
+    /// assert_eq!(dom.encoding(), Some("windows-1251"));
+    /// ```
+    pub fn from_bytes(bytes: &[u8]) -> DOM {
+        DOM::_from_bytes(bytes, None)
+    }
+
+    /// Construct a new `DOM` object and parse HTML (or XML like `new`) like `from_bytes`,
+    /// with an encoding label (like the charset of an HTTP response) overriding `<meta>` elements.
+    ///
+    /// A byte order mark still takes precedence and unknown labels are ignored.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::from_bytes_with_encoding(b"<meta charset=utf-8><p>\x82\xa0</p>", "Shift_JIS");
+    /// assert_eq!(dom.at("p").unwrap().text(), "あ");
+    /// assert_eq!(dom.encoding(), Some("Shift_JIS"));
+    /// ```
+    pub fn from_bytes_with_encoding(bytes: &[u8], encoding: &str) -> DOM {
+        DOM::_from_bytes(bytes, Some(encoding))
+    }
+
+    fn _from_bytes(bytes: &[u8], label: Option<&str>) -> DOM {
+        let (html, encoding) = encoding::decode(bytes, label);
+        let mut tree = html::parse(&html, html::detect_xml(&html));
+        tree.encoding = Some(encoding.name());
+        DOM::_new(tree)
+    }
+
+    /// Get the character encoding of a document parsed by `from_bytes`
+    /// (labels like ISO-8859-1 are reported with the name of the encoding actually used, like windows-1252).
+    pub fn encoding(&self) -> Option<&'static str> {
+        self._read().encoding
+    }
+
+    /// Check if the document has been parsed in XML mode.
+    pub fn is_xml(&self) -> bool {
+        self._read().xml
+    }
+
+    fn _new(tree: Tree) -> DOM {
+        DOM { id: tree.root(), _pin: tree.pin(tree.root()), tree: Arc::new(RwLock::new(tree)), name: None }
+    }
+
+    // DOM object for another node of the same document
+    fn _dom(&self, tree: &Tree, id: NodeId) -> DOM {
+        DOM { tree: self.tree.clone(), id, name: tree.get_tag_name(id).cloned(), _pin: tree.pin(id) }
+    }
+
+    fn _read(&self) -> RwLockReadGuard<'_, Tree> {
+        self.tree.read().unwrap()
+    }
+
+    fn _write(&self) -> RwLockWriteGuard<'_, Tree> {
+        self.tree.write().unwrap()
     }
 
     /// Find all ancestor elements of the current element matching the optional CSS selector
@@ -37,39 +196,224 @@ impl DOM {
     /// assert_eq!(ancestors, ["body", "html"]);
     /// ```
     pub fn ancestors(&self, selector: Option<&str>) -> Vec<DOM> {
+        self.ancestors_with(_compile(selector).as_ref())
+    }
+
+    /// Same as `ancestors`, but with a precompiled CSS selector.
+    pub fn ancestors_with(&self, selector: Option<&Selector>) -> Vec<DOM> {
+        let tree = self._read();
         let mut ancestors = Vec::new();
-        let mut node = self.tree.clone();
-        while let Some(parent) = node.get_parent() {
-            if parent.is_tag() && (selector.is_none() || css::matches(&parent, selector.unwrap())) {
-                ancestors.push(DOM { root: self.root.clone(), tree: parent.clone() });
+        let mut node = self.id;
+        while let Some(parent) = tree.get_parent(node) {
+            if tree.is_tag(parent) && _filter(&tree, parent, selector) {
+                ancestors.push(self._dom(&tree, parent));
             }
             node = parent;
         }
         ancestors
     }
 
+    /// Same as `ancestors`, but return a lazy iterator (from the parent element up).
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<div><section><p><b>Hello</b></p></section></div>");
+    /// let section = dom.at("b").unwrap().ancestors_iter(None).find(|x| x.tag() == Some("section"));
+    /// assert!(section.is_some());
+    /// ```
+    pub fn ancestors_iter(&self, selector: Option<&str>) -> Elements {
+        Elements::new(self, Axis::Ancestors, _compile(selector))
+    }
+
     /// Find first descendant element of the current element matching the CSS selector and return it as a DOM object,
     /// or `None` if none could be found.
     pub fn at(&self, selector: &str) -> Option<DOM> {
-        if let Some(node) = css::select_one(&self.tree, selector) {
-            return Some(DOM { root: self.root.clone(), tree: node })
-        }
-        None
+        self.at_with(&css::compile(selector))
+    }
+
+    /// Same as `at`, but return an error for an invalid CSS selector.
+    pub fn try_at(&self, selector: &str) -> Result<Option<DOM>, SelectorError> {
+        Ok(self.at_with(&Selector::parse(selector)?))
+    }
+
+    /// Same as `at`, but with a precompiled CSS selector.
+    ///
+    /// ```
+    /// use victoria_dom::{DOM, Selector};
+    /// let selector = Selector::parse("h1.title").unwrap();
+    /// for html in &["<h1 class=\"title\">A</h1>", "<div><h1 class=\"title\">B</h1></div>"] {
+    ///     assert!(DOM::new(html).at_with(&selector).is_some());
+    /// }
+    /// ```
+    pub fn at_with(&self, selector: &Selector) -> Option<DOM> {
+        let tree = self._read();
+        css::select_one(&tree, self.id, selector).map(|x| self._dom(&tree, x))
     }
 
     /// The current element tag name.
     pub fn tag(&self) -> Option<&str> {
-        self.tree.get_tag_name()
+        self.name.as_deref()
     }
 
-    /// The current element attribute2value map.
-    pub fn attrs(&self) -> BTreeMap<String, Option<String>> {
-        self.tree.get_tag_attrs().map_or_else(|| BTreeMap::new(), |x| x.clone())
+    /// The current element namespace URI, or `None` if the element is not in a namespace.
+    ///
+    /// Namespaces are declared with `xmlns` and `xmlns:prefix` attributes on the element or its ancestors,
+    /// HTML elements (and SVG and MathML elements embedded in HTML) get their namespaces implicitly.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new_xml("<root xmlns:dc=\"http://purl.org/dc/elements/1.1/\"><dc:creator>A</dc:creator><item/></root>");
+    /// assert_eq!(dom.at("creator").unwrap().namespace().unwrap(), "http://purl.org/dc/elements/1.1/");
+    /// assert_eq!(dom.at("item").unwrap().namespace(), None);
+    /// assert_eq!(DOM::new("<svg><rect/></svg>").at("rect").unwrap().namespace().unwrap(), "http://www.w3.org/2000/svg");
+    /// assert_eq!(DOM::new("<p>A</p>").at("p").unwrap().namespace().unwrap(), "http://www.w3.org/1999/xhtml");
+    /// ```
+    pub fn namespace(&self) -> Option<String> {
+        self._read().get_namespace(self.id).map(|x| x.to_owned())
+    }
+
+    /// The source ranges of the current node, `None` for the root and nodes that have not been parsed with the document.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let html = "<ul>\n  <li id=\"a\">A</li>\n  <li>B\n</ul>";
+    /// let dom = DOM::new(html);
+    /// let span = dom.at("#a").unwrap().span().unwrap();
+    /// assert_eq!(&html[span.open.start.offset .. span.open.end.offset], "<li id=\"a\">");
+    /// assert_eq!((span.open.start.line, span.open.start.column), (2, 3));
+    /// let close = span.close.unwrap();
+    /// assert_eq!((close.start.offset, close.end.offset, close.start.column), (19, 24, 15));
+    /// assert_eq!(dom.find("li")[1].span().unwrap().close, None);
+    /// ```
+    pub fn span(&self) -> Option<NodeSpan> {
+        self._read()[self.id].span
+    }
+
+    /// The current element attributes in source order.
+    pub fn attrs(&self) -> Attrs {
+        self._read().get_tag_attrs(self.id).cloned().unwrap_or_default()
     }
 
     /// The current element attribute value, or `None` if there are no attribute with the name or value.
-    pub fn attr(&self, name: &str) -> Option<&str> {
-        self.tree.get_tag_attrs().and_then(|x| x.get(name)).and_then(|x| x.as_ref()).map(|x| x.as_str())
+    pub fn attr(&self, name: &str) -> Option<String> {
+        self._read().get_tag_attrs(self.id).and_then(|x| x.get(name).cloned()).and_then(|x| x)
+    }
+
+    /// Set the current element attribute value (`None` for an attribute without value).
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<a href=\"/foo\">Foo</a>");
+    /// dom.at("a").unwrap().set_attr("href", "/bar").set_attr("download", None);
+    /// assert_eq!(dom.to_string(), "<a download href=\"/bar\">Foo</a>");
+    /// ```
+    pub fn set_attr<'a, T: Into<Option<&'a str>>>(&self, name: &str, value: T) -> &DOM {
+        let mut tree = self._write();
+        let name = html::tag_name(name, tree.xml);
+        tree.update_tag_attrs(self.id, |attrs| { attrs.insert(name, value.into().map(|x| x.to_owned())); });
+        self
+    }
+
+    /// Merge the attribute/value pairs into the current element attributes, new attributes are added in the given order.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<img src=\"a.png\">");
+    /// dom.at("img").unwrap().set_attrs(vec![("width", Some("10")), ("src", Some("b.png")), ("alt", None)]);
+    /// assert_eq!(dom.at("img").unwrap().attrs().keys().collect::<Vec<_>>(), ["src", "width", "alt"]);
+    /// assert_eq!(dom.to_string(), "<img alt src=\"b.png\" width=\"10\">");
+    /// ```
+    pub fn set_attrs<I, K, V>(&self, attrs: I) -> &DOM where I: IntoIterator<Item = (K, Option<V>)>, K: Into<String>, V: Into<String> {
+        let mut tree = self._write();
+        let xml = tree.xml;
+        let attrs = attrs.into_iter().map(|(key, value)| (html::tag_name(&key.into(), xml), value.map(Into::into)));
+        tree.update_tag_attrs(self.id, |x| x.extend(attrs));
+        self
+    }
+
+    /// Remove the current element attribute.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<img src=\"pixel.gif\" width=\"1\">");
+    /// dom.at("img").unwrap().remove_attr("width");
+    /// assert_eq!(dom.to_string(), "<img src=\"pixel.gif\">");
+    /// ```
+    pub fn remove_attr(&self, name: &str) -> &DOM {
+        let mut tree = self._write();
+        let name = html::tag_name(name, tree.xml);
+        tree.update_tag_attrs(self.id, |attrs| { attrs.remove(&name); });
+        self
+    }
+
+    /// Check if the current element has the class.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<p class=\"big  red\">Hello</p>");
+    /// assert!(dom.at("p").unwrap().has_class("red"));
+    /// assert!(!dom.at("p").unwrap().has_class("blue"));
+    /// ```
+    pub fn has_class(&self, class: &str) -> bool {
+        self._classes().iter().any(|x| x == class)
+    }
+
+    /// Add the class to the current element, unless it is already there.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<p class=\"big\">Hello</p>");
+    /// dom.at("p").unwrap().add_class("red").add_class("big");
+    /// assert_eq!(dom.to_string(), "<p class=\"big red\">Hello</p>");
+    /// ```
+    pub fn add_class(&self, class: &str) -> &DOM {
+        let mut classes = self._classes();
+        if !classes.iter().any(|x| x == class) {
+            classes.push(class.to_owned());
+            self._set_classes(classes);
+        }
+        self
+    }
+
+    /// Remove the class from the current element, the `class` attribute is removed together with the last class.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<p class=\"big red\">Hello</p>");
+    /// dom.at("p").unwrap().remove_class("big");
+    /// assert_eq!(dom.to_string(), "<p class=\"red\">Hello</p>");
+    /// ```
+    pub fn remove_class(&self, class: &str) -> &DOM {
+        let mut classes = self._classes();
+        if classes.iter().any(|x| x == class) {
+            classes.retain(|x| x != class);
+            self._set_classes(classes);
+        }
+        self
+    }
+
+    /// Add the class to the current element if it is missing, otherwise remove it.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<p class=\"big\">Hello</p>");
+    /// dom.at("p").unwrap().toggle_class("big").toggle_class("red");
+    /// assert_eq!(dom.to_string(), "<p class=\"red\">Hello</p>");
+    /// ```
+    pub fn toggle_class(&self, class: &str) -> &DOM {
+        if self.has_class(class) { self.remove_class(class) } else { self.add_class(class) }
+    }
+
+    fn _classes(&self) -> Vec<String> {
+        self.attr("class").map_or_else(Vec::new, |x| x.split_whitespace().map(|x| x.to_owned()).collect())
+    }
+
+    fn _set_classes(&self, classes: Vec<String>) {
+        if classes.is_empty() {
+            self.remove_attr("class");
+        } else {
+            self.set_attr("class", classes.join(" ").as_str());
+        }
     }
 
     /// Find all child elements of the current element matching the CSS selector and return a Vector of DOM objects of these elements.
@@ -81,15 +425,33 @@ impl DOM {
     /// assert_eq!(childs, ["a", "b"]);
     /// ```
     pub fn childs(&self, selector: Option<&str>) -> Vec<DOM> {
-        self.tree.get_childs().unwrap_or(Vec::new()).into_iter().filter_map(|x|
-            if x.is_tag() && (selector.is_none() || css::matches(&x, selector.unwrap())) {
-                Some(DOM { root: self.root.clone(), tree: x })
+        self.childs_with(_compile(selector).as_ref())
+    }
+
+    /// Same as `childs`, but with a precompiled CSS selector.
+    pub fn childs_with(&self, selector: Option<&Selector>) -> Vec<DOM> {
+        let tree = self._read();
+        tree.get_childs(self.id).filter_map(|x|
+            if tree.is_tag(x) && _filter(&tree, x, selector) {
+                Some(self._dom(&tree, x))
             } else {
                 None
             }
         ).collect()
     }
 
+    /// Same as `childs`, but return a lazy iterator.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<ul><li>A</li><li class=\"x\">B</li><li class=\"x\">C</li></ul>");
+    /// let texts: Vec<_> = dom.at("ul").unwrap().children_iter(Some(".x")).map(|x| x.text()).collect();
+    /// assert_eq!(texts, ["B", "C"]);
+    /// ```
+    pub fn children_iter(&self, selector: Option<&str>) -> Elements {
+        Elements::new(self, Axis::Children, _compile(selector))
+    }
+
     /// Find all descendant elements of the current element matching the CSS selector and return a Vector of DOM objects of these elements.
     ///
     /// ```
@@ -99,12 +461,73 @@ impl DOM {
     /// assert_eq!(elems, ["a", "c", "b"]);
     /// ```
     pub fn find(&self, selector: &str) -> Vec<DOM> {
-        css::select(&self.tree, selector, 0).into_iter().map(|x| DOM { root: self.root.clone(), tree: x }).collect()
+        self.find_with(&css::compile(selector))
+    }
+
+    /// Same as `find`, but return an error for an invalid CSS selector.
+    ///
+    /// ```
+    /// use victoria_dom::{DOM, SelectorErrorKind};
+    /// let dom = DOM::new("<div foo=\"bar\">A</div>");
+    /// let err = dom.try_find("div[foo=").unwrap_err();
+    /// assert_eq!((err.kind, err.position), (SelectorErrorKind::InvalidAttribute, 3));
+    /// assert_eq!(dom.try_find("div[foo=bar]").unwrap().len(), 1);
+    /// ```
+    pub fn try_find(&self, selector: &str) -> Result<Vec<DOM>, SelectorError> {
+        Ok(self.find_with(&Selector::parse(selector)?))
+    }
+
+    /// Same as `find`, but with a precompiled CSS selector.
+    ///
+    /// ```
+    /// use victoria_dom::{DOM, Selector};
+    /// let selector = Selector::parse("a[href]").unwrap();
+    /// let dom = DOM::new("<p><a href=\"/a\">A</a><a>B</a></p><a href=\"/c\">C</a>");
+    /// assert_eq!(dom.find_with(&selector).len(), 2);
+    /// assert_eq!(dom.at("p").unwrap().find_with(&selector).len(), 1);
+    /// ```
+    pub fn find_with(&self, selector: &Selector) -> Vec<DOM> {
+        let tree = self._read();
+        css::select(&tree, self.id, selector, 0).into_iter().map(|x| self._dom(&tree, x)).collect()
+    }
+
+    /// Same as `find`, but return a lazy iterator, the document is only searched as far as the iterator is advanced.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<p>A</p><p class=\"x\">B</p><p class=\"x\">C</p>");
+    /// let first: Vec<_> = dom.find_iter("p.x").take(1).map(|x| x.text()).collect();
+    /// assert_eq!(first, ["B"]);
+    /// ```
+    pub fn find_iter(&self, selector: &str) -> Elements {
+        Elements::new(self, Axis::Descendants, Some(css::compile(selector)))
+    }
+
+    /// Return a lazy iterator over all descendant elements of the current element in document order.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<div><p><b>A</b></p><i>B</i></div>");
+    /// let tags: Vec<_> = dom.descendants().map(|x| x.tag().unwrap().to_owned()).collect();
+    /// assert_eq!(tags, ["div", "p", "b", "i"]);
+    /// ```
+    pub fn descendants(&self) -> Elements {
+        Elements::new(self, Axis::Descendants, None)
     }
 
     /// Check if the current element matches the CSS selector.
     pub fn matches(&self, selector: &str) -> bool {
-        css::matches(&self.tree, selector)
+        selector.is_empty() || self.matches_with(&css::compile(selector))
+    }
+
+    /// Same as `matches`, but return an error for an invalid CSS selector.
+    pub fn try_matches(&self, selector: &str) -> Result<bool, SelectorError> {
+        Ok(self.matches_with(&Selector::parse(selector)?))
+    }
+
+    /// Same as `matches`, but with a precompiled CSS selector.
+    pub fn matches_with(&self, selector: &Selector) -> bool {
+        css::matches(&self._read(), self.id, selector)
     }
 
     /// Find all sibling elements after the current element matching the CSS selector and return a Vector of DOM objects of these elements.
@@ -116,14 +539,32 @@ impl DOM {
     /// assert_eq!(elems, ["b"]);
     /// ```
     pub fn following(&self, selector: Option<&str>) -> Vec<DOM> {
-        self._siblings().into_iter().skip_while(|x| x.id != self.tree.id).skip(1)
-            .filter(|x| selector.is_none() || css::matches(x, selector.unwrap()))
-            .map(|x| DOM { root: self.root.clone(), tree: x }).collect()
+        self.following_with(_compile(selector).as_ref())
+    }
+
+    /// Same as `following`, but with a precompiled CSS selector.
+    pub fn following_with(&self, selector: Option<&Selector>) -> Vec<DOM> {
+        let tree = self._read();
+        let mut following = Vec::new();
+        let mut node = self.id;
+        while let Some(sibling) = tree[node].next_sibling {
+            if tree.is_tag(sibling) && _filter(&tree, sibling, selector) {
+                following.push(self._dom(&tree, sibling));
+            }
+            node = sibling;
+        }
+        following
     }
 
     /// Return a DOM object for next sibling element, or `None` if there are no more siblings.
     pub fn next(&self) -> Option<DOM> {
-        self._siblings().into_iter().skip_while(|x| x.id != self.tree.id).skip(1).next().map(|x| DOM { root: self.root.clone(), tree: x })
+        let tree = self._read();
+        let mut node = self.id;
+        while let Some(sibling) = tree[node].next_sibling {
+            if tree.is_tag(sibling) { return Some(self._dom(&tree, sibling)); }
+            node = sibling;
+        }
+        None
     }
 
     /// Find all sibling elements before the current element matching the CSS selector and return a Vector of DOM objects of these elements.
@@ -135,31 +576,52 @@ impl DOM {
     /// assert_eq!(elems, ["a"]);
     /// ```
     pub fn preceding(&self, selector: Option<&str>) -> Vec<DOM> {
-        self._siblings().into_iter().take_while(|x| x.id != self.tree.id)
-            .filter(|x| selector.is_none() || css::matches(x, selector.unwrap()))
-            .map(|x| DOM { root: self.root.clone(), tree: x }).collect()
+        self.preceding_with(_compile(selector).as_ref())
+    }
+
+    /// Same as `preceding`, but with a precompiled CSS selector.
+    pub fn preceding_with(&self, selector: Option<&Selector>) -> Vec<DOM> {
+        let tree = self._read();
+        let mut preceding = Vec::new();
+        let mut node = self.id;
+        while let Some(sibling) = tree[node].prev_sibling {
+            if tree.is_tag(sibling) && _filter(&tree, sibling, selector) {
+                preceding.push(self._dom(&tree, sibling));
+            }
+            node = sibling;
+        }
+        preceding.reverse();
+        preceding
     }
 
     /// Return a DOM object for the previous sibling element, or `None` if there are no more siblings.
     pub fn prev(&self) -> Option<DOM> {
-        self._siblings().into_iter().take_while(|x| x.id != self.tree.id).last().map(|x| DOM { root: self.root.clone(), tree: x })
+        let tree = self._read();
+        let mut node = self.id;
+        while let Some(sibling) = tree[node].prev_sibling {
+            if tree.is_tag(sibling) { return Some(self._dom(&tree, sibling)); }
+            node = sibling;
+        }
+        None
     }
 
-    fn _siblings(&self) -> Vec<Rc<TreeNode>> {
-        self.tree.get_parent()
-            .and_then(|x| x.get_childs())
-            .map(|x| x.into_iter().filter(|v| v.is_tag()).collect::<Vec<_>>())
-            .unwrap_or(Vec::new())
+    /// Return a lazy iterator over all sibling elements of the current element (excluding itself)
+    /// matching the optional CSS selector.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<ul><li>A</li><li id=\"b\">B</li><li>C</li></ul>");
+    /// let texts: Vec<_> = dom.at("#b").unwrap().siblings_iter(None).map(|x| x.text()).collect();
+    /// assert_eq!(texts, ["A", "C"]);
+    /// ```
+    pub fn siblings_iter(&self, selector: Option<&str>) -> Elements {
+        Elements::new(self, Axis::Siblings, _compile(selector))
     }
 
     /// Return a DOM object for the parent of the current element, or `None` if this element has no parent.
     pub fn parent(&self) -> Option<DOM> {
-        self.tree.get_parent().map(|x| DOM { root: self.root.clone(), tree: x })
-    }
-
-    /// Render the current element and its content to HTML.
-    pub fn to_string(&self) -> String {
-        html::render(&self.tree)
+        let tree = self._read();
+        tree.get_parent(self.id).map(|x| self._dom(&tree, x))
     }
 
     /// Extract text content from the current element only (not including child elements) with smart whitespace trimming.
@@ -207,40 +669,523 @@ impl DOM {
     }
 
     fn _text(&self, recursive: bool, trim: bool) -> String {
+        let tree = self._read();
+
         // Try to detect "pre" tag
         let mut under_pre_tag = false;
         if trim {
-            let mut node = self.tree.clone();
-            loop {
-                if let html::NodeElem::Tag { ref name, .. } = node.elem {
-                    if name == "pre" {
-                        under_pre_tag = true;
-                        break;
-                    }
+            let mut node = Some(self.id);
+            while let Some(x) = node {
+                if tree.get_tag_name(x).is_some_and(|x| &**x == "pre") {
+                    under_pre_tag = true;
+                    break;
                 }
-                if node.get_parent().is_some() { node = node.get_parent().unwrap(); } else { break; }
+                node = tree.get_parent(x);
             }
         }
 
-        match self.tree.get_childs() {
-            Some(nodes) => _nodes_text(&nodes, recursive, trim && !under_pre_tag),
-            _ => String::new(),
-        }
+        _nodes_text(&tree, tree.get_childs(self.id), recursive, trim && !under_pre_tag)
     }
 
-    /// Return content of the current element.
+    /// Return content of the current element, or the raw content of the current non-element node.
     ///
     /// ```
     /// use victoria_dom::DOM;
-    /// let dom = DOM::new("<div><b>Test</b></div>");
-    /// assert_eq!(dom.at("div").unwrap().content(), "<b>Test</b>");
+    /// let dom = DOM::new("<div><b>Test</b><!-- note --></div>");
+    /// assert_eq!(dom.at("div").unwrap().content(), "<b>Test</b><!-- note -->");
+    /// assert_eq!(dom.at("div").unwrap().child_nodes()[1].content(), " note ");
     /// ```
     pub fn content(&self) -> String {
-        self.tree.get_childs().unwrap().into_iter().map(|x| html::render(&x)).collect::<Vec<_>>().join("")
+        let tree = self._read();
+        if let Some(content) = tree.get_text_content(self.id) { return content.to_owned(); }
+        html::render_content(&tree, self.id, &RenderOptions::default())
+    }
+
+    /// The current node type.
+    ///
+    /// ```
+    /// use victoria_dom::{DOM, NodeType};
+    /// let dom = DOM::new("<!DOCTYPE html><p>Hello</p>");
+    /// let types: Vec<_> = dom.child_nodes().iter().map(|x| x.node_type()).collect();
+    /// assert_eq!(types, [NodeType::Doctype, NodeType::Tag]);
+    /// assert_eq!(dom.node_type(), NodeType::Root);
+    /// ```
+    pub fn node_type(&self) -> NodeType {
+        self._read().get_node_type(self.id)
+    }
+
+    /// The kind of the current non-element node, or `None` for elements and the root.
+    ///
+    /// ```
+    /// use victoria_dom::{DOM, TextKind};
+    /// let dom = DOM::new("<p>A</p><script>B</script><!-- C -->");
+    /// let kinds: Vec<_> = dom.descendant_nodes().iter().map(|x| x.text_kind()).collect();
+    /// assert_eq!(kinds, [None, Some(TextKind::Text), None, Some(TextKind::Raw), Some(TextKind::Comment)]);
+    /// ```
+    pub fn text_kind(&self) -> Option<TextKind> {
+        self._read().get_text_kind(self.id)
+    }
+
+    /// Return a Vector of DOM objects of all child nodes of the current element, including text, comments and other non-element nodes.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<p>Hello <b>World</b>!</p>");
+    /// let nodes: Vec<_> = dom.at("p").unwrap().child_nodes().iter().map(|x| x.to_string()).collect();
+    /// assert_eq!(nodes, ["Hello ", "<b>World</b>", "!"]);
+    /// ```
+    pub fn child_nodes(&self) -> Vec<DOM> {
+        let tree = self._read();
+        tree.get_childs(self.id).map(|x| self._dom(&tree, x)).collect()
+    }
+
+    /// Return a Vector of DOM objects of all descendant nodes of the current element in document order,
+    /// including text, comments and other non-element nodes.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<div><!--[if IE]><p>Old</p><![endif]--><p>New <!-- TODO --></p></div>");
+    /// let comments: Vec<_> = dom.descendant_nodes().iter().filter_map(|x| x.comment()).collect();
+    /// assert_eq!(comments, ["[if IE]><p>Old</p><![endif]", " TODO "]);
+    /// ```
+    pub fn descendant_nodes(&self) -> Vec<DOM> {
+        let tree = self._read();
+        let mut nodes = Vec::new();
+        let mut next = tree.next_in_scope(self.id, self.id);
+        while let Some(node) = next {
+            nodes.push(self._dom(&tree, node));
+            next = tree.next_in_scope(node, self.id);
+        }
+        nodes
+    }
+
+    /// The comment text, or `None` if the current node is not a comment.
+    pub fn comment(&self) -> Option<String> {
+        self._text_content(NodeType::Comment)
+    }
+
+    /// The document type declaration content, or `None` if the current node is not a doctype.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<!DOCTYPE html><html></html>");
+    /// assert_eq!(dom.child_nodes()[0].doctype().unwrap(), " html");
+    /// ```
+    pub fn doctype(&self) -> Option<String> {
+        self._text_content(NodeType::Doctype)
+    }
+
+    /// The processing instruction content, or `None` if the current node is not a processing instruction.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<?xml-stylesheet href=\"style.xsl\"?><root/>");
+    /// assert_eq!(dom.child_nodes()[0].processing_instruction().unwrap(), "xml-stylesheet href=\"style.xsl\"");
+    /// ```
+    pub fn processing_instruction(&self) -> Option<String> {
+        self._text_content(NodeType::ProcessingInstruction)
+    }
+
+    /// The CDATA section content, or `None` if the current node is not a CDATA section.
+    pub fn cdata(&self) -> Option<String> {
+        self._text_content(NodeType::CData)
+    }
+
+    fn _text_content(&self, node_type: NodeType) -> Option<String> {
+        let tree = self._read();
+        if tree.get_node_type(self.id) != node_type { return None; }
+        tree.get_text_content(self.id).map(|x| x.to_owned())
+    }
+
+    /// Render the current element and its content with the options.
+    pub fn render(&self, options: &RenderOptions) -> String {
+        html::render(&self._read(), self.id, options)
+    }
+
+    /// Render the current element and its content to XML, empty elements are self-closing
+    /// and attributes without value get their name as value.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<p>A<br><input type=\"checkbox\" checked><span></span></p>");
+    /// assert_eq!(dom.to_xml(), "<p>A<br/><input checked=\"checked\" type=\"checkbox\"/><span/></p>");
+    /// assert_eq!(DOM::new_xml("<a><b></b></a>").to_string(), "<a><b/></a>");
+    /// ```
+    pub fn to_xml(&self) -> String {
+        self.render(&RenderOptions { xml: Some(true), ..RenderOptions::default() })
+    }
+
+    /// Render the current element and its content with every block level element on its own line, indented by `indent` per level.
+    /// Inline elements stay on one line and the content of `pre`, `textarea`, `script` and similar elements is left untouched.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<div><h1>Hello <b>World</b></h1><ul><li>A</li><li>B</li></ul><pre> 1\n 2</pre></div>");
+    /// assert_eq!(dom.to_pretty_string("  "), "<div>\n  <h1>Hello <b>World</b></h1>\n  <ul>\n    <li>A</li>\n    <li>B</li>\n  </ul>\n  <pre> 1\n 2</pre>\n</div>\n");
+    /// ```
+    pub fn to_pretty_string(&self, indent: &str) -> String {
+        self.render(&RenderOptions { indent: Some(indent.to_owned()), ..RenderOptions::default() })
+    }
+
+    /// Append HTML/DOM fragment after the current element.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<div><h1>Test</h1></div>");
+    /// dom.at("h1").unwrap().append("<h2>123</h2>");
+    /// assert_eq!(dom.to_string(), "<div><h1>Test</h1><h2>123</h2></div>");
+    /// ```
+    pub fn append<'a, T: Into<Fragment<'a>>>(&self, fragment: T) -> &DOM {
+        self._add(fragment.into(), true)
+    }
+
+    /// Append HTML/DOM fragment to the current element content.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<div><h1>Test</h1></div>");
+    /// dom.at("div").unwrap().append_content("<h2>123</h2>");
+    /// assert_eq!(dom.to_string(), "<div><h1>Test</h1><h2>123</h2></div>");
+    /// ```
+    pub fn append_content<'a, T: Into<Fragment<'a>>>(&self, fragment: T) -> &DOM {
+        self._content(fragment.into(), false)
+    }
+
+    /// Prepend HTML/DOM fragment before the current element.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<div><h2>Test</h2></div>");
+    /// dom.at("h2").unwrap().prepend("<h1>123</h1>");
+    /// assert_eq!(dom.to_string(), "<div><h1>123</h1><h2>Test</h2></div>");
+    /// ```
+    pub fn prepend<'a, T: Into<Fragment<'a>>>(&self, fragment: T) -> &DOM {
+        self._add(fragment.into(), false)
+    }
+
+    /// Prepend HTML/DOM fragment to the current element content.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<div><h2>Test</h2></div>");
+    /// dom.at("div").unwrap().prepend_content("<h1>123</h1>");
+    /// assert_eq!(dom.to_string(), "<div><h1>123</h1><h2>Test</h2></div>");
+    /// ```
+    pub fn prepend_content<'a, T: Into<Fragment<'a>>>(&self, fragment: T) -> &DOM {
+        self._content(fragment.into(), true)
+    }
+
+    /// Replace the current element with HTML/DOM fragment and return a DOM object for the parent element.
+    /// For the root element the whole content is replaced.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<div><h1>Test</h1></div>");
+    /// assert_eq!(dom.at("h1").unwrap().replace("<h2>123</h2>").to_string(), "<div><h2>123</h2></div>");
+    /// ```
+    pub fn replace<'a, T: Into<Fragment<'a>>>(&self, fragment: T) -> DOM {
+        let fragment = fragment.into().nodes(self.is_xml());
+        let mut tree = self._write();
+        let nodes = tree.adopt(fragment);
+
+        if let NodeElem::Root = tree[self.id].elem {
+            while let Some(child) = tree[self.id].first_child { tree.remove(child); }
+            for node in nodes { tree.insert_before(self.id, None, node); }
+            return self._dom(&tree, self.id);
+        }
+
+        match tree.get_parent(self.id) {
+            Some(parent) => {
+                for node in nodes { tree.insert_before(parent, Some(self.id), node); }
+                tree.remove(self.id);
+                self._dom(&tree, parent)
+            },
+            None => self._dom(&tree, self.id),
+        }
+    }
+
+    /// Remove the current element and return a DOM object for the parent element.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<div><h1>Test</h1><img src=\"pixel.gif\"></div>");
+    /// assert_eq!(dom.at("img").unwrap().remove().to_string(), "<div><h1>Test</h1></div>");
+    /// ```
+    pub fn remove(&self) -> DOM {
+        self.replace("")
+    }
+
+    /// Wrap HTML/DOM fragment around the current element, placing it as the last child of the first innermost element.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<b>Test</b>");
+    /// dom.at("b").unwrap().wrap("<p>123</p>");
+    /// assert_eq!(dom.to_string(), "<p>123<b>Test</b></p>");
+    /// ```
+    pub fn wrap<'a, T: Into<Fragment<'a>>>(&self, fragment: T) -> &DOM {
+        self._wrap(fragment.into(), false)
+    }
+
+    /// Wrap HTML/DOM fragment around the current element content, placing it after the content of the first innermost element.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<p>Test</p>");
+    /// dom.at("p").unwrap().wrap_content("<b>123</b>");
+    /// assert_eq!(dom.to_string(), "<p><b>123Test</b></p>");
+    /// ```
+    pub fn wrap_content<'a, T: Into<Fragment<'a>>>(&self, fragment: T) -> &DOM {
+        self._wrap(fragment.into(), true)
+    }
+
+    /// Remove the current element while preserving its content and return a DOM object for the parent element.
+    ///
+    /// ```
+    /// use victoria_dom::DOM;
+    /// let dom = DOM::new("<div><h1>Test</h1></div>");
+    /// assert_eq!(dom.at("h1").unwrap().strip().to_string(), "<div>Test</div>");
+    /// ```
+    pub fn strip(&self) -> DOM {
+        let mut tree = self._write();
+        match tree.get_parent(self.id) {
+            Some(parent) if tree.is_tag(self.id) => {
+                while let Some(child) = tree[self.id].first_child {
+                    tree.detach(child);
+                    tree.insert_before(parent, Some(self.id), child);
+                }
+                tree.remove(self.id);
+                self._dom(&tree, parent)
+            },
+            _ => self._dom(&tree, self.id),
+        }
+    }
+
+    fn _add(&self, fragment: Fragment, after: bool) -> &DOM {
+        let fragment = fragment.nodes(self.is_xml());
+        let mut tree = self._write();
+        if let Some(parent) = tree.get_parent(self.id) {
+            let before = if after { tree[self.id].next_sibling } else { Some(self.id) };
+            for node in tree.adopt(fragment) { tree.insert_before(parent, before, node); }
+        }
+        self
+    }
+
+    fn _content(&self, fragment: Fragment, start: bool) -> &DOM {
+        let fragment = fragment.nodes(self.is_xml());
+        let mut tree = self._write();
+        if let NodeElem::Text { .. } = tree[self.id].elem { return self; }
+
+        let before = if start { tree[self.id].first_child } else { None };
+        for node in tree.adopt(fragment) { tree.insert_before(self.id, before, node); }
+        self
+    }
+
+    fn _wrap(&self, fragment: Fragment, content: bool) -> &DOM {
+        let fragment = fragment.nodes(self.is_xml());
+        let mut tree = self._write();
+
+        let content = match tree[self.id].elem {
+            NodeElem::Root => true,
+            NodeElem::Tag { .. } => content,
+            _ => false,
+        };
+
+        // Find innermost tag
+        let nodes = tree.adopt(fragment);
+        let mut innermost = None;
+        let mut first = nodes.iter().cloned().find(|&x| tree.is_tag(x));
+        while let Some(node) = first {
+            first = tree.get_childs(node).find(|&x| tree.is_tag(x));
+            innermost = Some(node);
+        }
+        let innermost = match innermost {
+            Some(x) => x,
+            None => return self,
+        };
+
+        // Wrap content
+        if content {
+            while let Some(child) = tree[self.id].first_child {
+                tree.detach(child);
+                tree.insert_before(innermost, None, child);
+            }
+            for node in nodes { tree.insert_before(self.id, None, node); }
+        }
+
+        // Wrap element
+        else if let Some(parent) = tree.get_parent(self.id) {
+            for node in nodes { tree.insert_before(parent, Some(self.id), node); }
+            tree.detach(self.id);
+            tree.insert_before(innermost, None, self.id);
+        }
+
+        self
+    }
+}
+
+impl fmt::Display for DOM {
+    /// Render the current element and its content to HTML (or XML for documents parsed in XML mode).
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.render(&RenderOptions::default()))
+    }
+}
+
+/// HTML/DOM fragment for the `DOM` mutation methods.
+///
+/// An HTML fragment is parsed in the same mode (HTML or XML) as the document it is inserted into.
+/// A `DOM` fragment is copied, so the source document is left untouched.
+#[derive(Debug)]
+pub enum Fragment<'a> {
+    /// HTML fragment that gets parsed before insertion.
+    Html(&'a str),
+    /// Another `DOM` object (the root object contributes its content).
+    Dom(&'a DOM),
+}
+
+impl<'a> Fragment<'a> {
+    // Fragment nodes are the children of the returned tree root
+    fn nodes(self, xml: bool) -> Tree {
+        match self {
+            Fragment::Html(html) => html::parse(html, xml),
+            Fragment::Dom(dom) => {
+                let source = dom._read();
+                let mut tree = Tree::new();
+                match source[dom.id].elem {
+                    NodeElem::Root => for child in source.get_childs(dom.id) { tree.copy_from(&source, child) },
+                    _ => tree.copy_from(&source, dom.id),
+                }
+                tree
+            },
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Fragment<'a> {
+    fn from(html: &'a str) -> Fragment<'a> {
+        Fragment::Html(html)
     }
 }
 
-fn _nodes_text(nodes: &Vec<Rc<TreeNode>>, recursive: bool, trim: bool) -> String {
+impl<'a> From<&'a String> for Fragment<'a> {
+    fn from(html: &'a String) -> Fragment<'a> {
+        Fragment::Html(html)
+    }
+}
+
+impl<'a> From<&'a DOM> for Fragment<'a> {
+    fn from(dom: &'a DOM) -> Fragment<'a> {
+        Fragment::Dom(dom)
+    }
+}
+
+/// Lazy iterator over elements of a document, see `DOM::find_iter`.
+///
+/// The document is only locked while the iterator is advanced, so it can be mutated between the steps
+/// (the iterator then continues from the current position of the last visited node, or from its old position if it has been removed).
+#[derive(Debug)]
+pub struct Elements {
+    tree: Arc<RwLock<Tree>>,
+    axis: Axis,
+    scope: NodeId,
+    next: Option<NodeId>,
+    last: Option<(NodeId, Option<NodeId>)>, // the last returned element and its successor (outside of it) at that time
+    selector: Option<Selector>,
+    _pins: Vec<Arc<()>>, // Keep the nodes above in the arena
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Axis {
+    Ancestors,
+    Children,
+    Descendants,
+    Siblings,
+}
+
+impl Elements {
+    fn new(dom: &DOM, axis: Axis, selector: Option<Selector>) -> Elements {
+        let tree = dom._read();
+        let next = match axis {
+            Axis::Ancestors => tree.get_parent(dom.id),
+            Axis::Children => tree[dom.id].first_child,
+            Axis::Descendants => tree.next_in_scope(dom.id, dom.id),
+            Axis::Siblings => tree.get_parent(dom.id).and_then(|x| tree[x].first_child),
+        };
+        let _pins = Some(dom.id).into_iter().chain(next).map(|x| tree.pin(x)).collect();
+        Elements { tree: dom.tree.clone(), axis, scope: dom.id, next, last: None, selector, _pins }
+    }
+
+    fn _successor(&self, tree: &Tree, current: NodeId) -> Option<NodeId> {
+        match self.axis {
+            Axis::Ancestors => tree.get_parent(current),
+            Axis::Children | Axis::Siblings => tree[current].next_sibling,
+            Axis::Descendants => tree.next_in_scope(current, self.scope),
+        }
+    }
+
+    // Check if the node is still in the document (and in scope for descendants)
+    fn _is_attached(&self, tree: &Tree, node: NodeId) -> bool {
+        let mut parent = tree.get_parent(node);
+        if self.axis != Axis::Descendants { return parent.is_some(); }
+        while let Some(x) = parent {
+            if x == self.scope { return true; }
+            parent = tree.get_parent(x);
+        }
+        false
+    }
+
+    // Descendants are matched within the scope like `find`
+    fn _filter(&self, tree: &Tree, node: NodeId) -> bool {
+        match self.selector {
+            Some(ref x) if self.axis == Axis::Descendants => css::matches_in_scope(tree, node, self.scope, x),
+            ref x => _filter(tree, node, x.as_ref()),
+        }
+    }
+}
+
+impl Iterator for Elements {
+    type Item = DOM;
+
+    fn next(&mut self) -> Option<DOM> {
+        let tree = self.tree.read().unwrap();
+
+        // Continue from the last element, or from its old successor if it has been removed
+        if let Some((last, successor)) = self.last.take() {
+            self.next = if self._is_attached(&tree, last) {
+                self._successor(&tree, last)
+            } else {
+                successor.filter(|&x| self._is_attached(&tree, x))
+            };
+        }
+
+        while let Some(current) = self.next {
+            self.next = self._successor(&tree, current);
+
+            if self.axis == Axis::Siblings && current == self.scope { continue; }
+            if tree.is_tag(current) && self._filter(&tree, current) {
+                let successor = match self.axis {
+                    Axis::Descendants => tree.next_after_in_scope(current, self.scope),
+                    _ => self.next,
+                };
+                self.last = Some((current, successor));
+                self._pins = [self.scope, current].iter().cloned().chain(successor).map(|x| tree.pin(x)).collect();
+                return Some(DOM { tree: self.tree.clone(), id: current, name: tree.get_tag_name(current).cloned(), _pin: tree.pin(current) });
+            }
+        }
+        None
+    }
+}
+
+// Optional selector filter (an empty selector matches everything)
+fn _compile(selector: Option<&str>) -> Option<Selector> {
+    selector.filter(|x| !x.is_empty()).map(css::compile)
+}
+
+fn _filter(tree: &Tree, node: NodeId, selector: Option<&Selector>) -> bool {
+    selector.is_none_or(|x| css::matches(tree, node, x))
+}
+
+fn _nodes_text<I: Iterator<Item = NodeId>>(tree: &Tree, nodes: I, recursive: bool, trim: bool) -> String {
     lazy_static! {
         static ref _RE1: Regex = Regex::new(r"\s+").unwrap();
         static ref _RE2: Regex = Regex::new(r"\S\z").unwrap();
@@ -250,16 +1195,16 @@ fn _nodes_text(nodes: &Vec<Rc<TreeNode>>, recursive: bool, trim: bool) -> String
 
     let mut text = String::new();
     for node in nodes {
-        let mut chunk = match node.elem {
-            html::NodeElem::Text { ref elem_type, ref content } => {
-                match elem_type.as_ref() {
-                    "text" if trim => _RE1.replace_all(content.trim(), " ").into_owned(),
-                    "text" | "raw" | "cdata" => content.to_owned(),
+        let mut chunk = match tree[node].elem {
+            NodeElem::Text { kind, ref content } => {
+                match kind {
+                    TextKind::Text if trim => _RE1.replace_all(content.trim(), " ").into_owned(),
+                    TextKind::Text | TextKind::Raw | TextKind::CData => content.to_owned(),
                     _ => String::new(),
                 }
             },
-            html::NodeElem::Tag { ref name, ref childs, .. } if recursive => {
-                _nodes_text(&childs.borrow(), true, trim && name != "pre")
+            NodeElem::Tag { ref name, .. } if recursive => {
+                _nodes_text(tree, tree.get_childs(node), true, trim && &**name != "pre")
             }
             _ => String::new(),
         };
@@ -276,3 +1221,46 @@ fn _nodes_text(nodes: &Vec<Rc<TreeNode>>, recursive: bool, trim: bool) -> String
     }
     text
 }
+
+#[cfg(test)]
+mod tests {
+    use super::DOM;
+
+    #[test]
+    fn arena_reuses_removed_nodes() {
+        let dom = DOM::new("<div><p>A</p><ul><li>1</li><li>2</li></ul></div>");
+        let capacity = dom._read().capacity();
+        for i in 0 .. 100 {
+            dom.at("p").unwrap().replace(&*format!("<p>{}</p>", i));
+            dom.at("ul").unwrap().append_content("<li>3</li>").at("li").unwrap().remove();
+            dom.at("ul").unwrap().wrap("<section></section>").parent().unwrap().strip();
+        }
+        assert_eq!(dom.to_string(), "<div><p>99</p><ul><li>3</li><li>3</li></ul></div>");
+        assert!(dom._read().capacity() <= capacity + 50);
+
+        // Removed nodes are kept while they are used
+        let p = dom.at("p").unwrap();
+        let b = DOM::new("<b>B</b>");
+        p.replace(&b);
+        for _ in 0 .. 10 { dom.at("b").unwrap().replace(&b); }
+        assert_eq!(p.to_string(), "<p>99</p>");
+        assert_eq!(p.parent().map(|x| x.to_string()), None);
+
+        // Elements visited by iterators too
+        let mut iter = dom.find_iter("li");
+        let li = iter.next().unwrap();
+        li.remove();
+        drop(li);
+        dom.at("div").unwrap().append_content("<i>I</i><i>J</i>");
+        assert_eq!(iter.next().map(|x| x.to_string()), Some("<li>3</li>".to_owned()));
+        assert!(iter.next().is_none());
+
+        // Pinned subtrees are not walked again on every mutation (removing all elements takes linear time)
+        let dom = DOM::new(&"<ul><li>x</li></ul>".repeat(20_000));
+        let lis = dom.find("li");
+        for li in &lis { li.remove(); }
+        for ul in dom.find("ul") { ul.remove(); }
+        assert_eq!(dom.to_string(), "");
+        assert_eq!(lis[0].to_string(), "<li>x</li>");
+    }
+}
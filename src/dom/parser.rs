@@ -0,0 +1,81 @@
+use super::DOM;
+use super::html::{PendingInput, TreeBuilder};
+use super::tokenizer::TokenBuffer;
+use util::utf8_decode;
+
+/// Incremental HTML (or XML) parser, building the same `DOM` as `DOM::new` from chunks of UTF-8 input.
+///
+/// Chunks can end anywhere, even inside a tag, an entity, raw text or a UTF-8 sequence.
+/// Invalid UTF-8 is replaced with `U+FFFD`.
+///
+/// ```
+/// use victoria_dom::Parser;
+/// let mut parser = Parser::new();
+/// parser.feed(b"<div><p>Fish &am");
+/// parser.feed(b"p; chips<scr");
+/// parser.feed(b"ipt>a < b</script>");
+/// let dom = parser.finish();
+/// assert_eq!(dom.to_string(), "<div><p>Fish &amp; chips<script>a < b</script></p></div>");
+/// ```
+#[derive(Debug)]
+pub struct Parser {
+    bytes: Vec<u8>, // incomplete UTF-8 sequence
+    pending: PendingInput, // input before XML mode has been detected
+    state: Option<(TokenBuffer, TreeBuilder)>,
+}
+
+impl Parser {
+    /// Construct a new `Parser` for HTML (or XML, if the document starts with an XML declaration).
+    pub fn new() -> Parser {
+        Parser { bytes: Vec::new(), pending: PendingInput::default(), state: None }
+    }
+
+    /// Construct a new `Parser` for XML.
+    pub fn new_xml() -> Parser {
+        Parser { bytes: Vec::new(), pending: PendingInput::default(), state: Some(Parser::_state(true)) }
+    }
+
+    fn _state(xml: bool) -> (TokenBuffer, TreeBuilder) {
+        Parser::_builder(TokenBuffer::new(xml))
+    }
+
+    fn _builder(tokens: TokenBuffer) -> (TokenBuffer, TreeBuilder) {
+        let builder = TreeBuilder::new(false, tokens.is_xml());
+        (tokens, builder)
+    }
+
+    /// Parse the next chunk of input.
+    pub fn feed(&mut self, chunk: &[u8]) {
+        self.bytes.extend_from_slice(chunk);
+        let text = utf8_decode(&mut self.bytes);
+        self._process(&text, false);
+    }
+
+    /// Parse the rest of the input and return the document.
+    pub fn finish(mut self) -> DOM {
+        let text = String::from_utf8_lossy(&self.bytes).into_owned();
+        self._process(&text, true);
+        let (_, builder) = self.state.unwrap();
+        DOM::_new(builder.finish().unwrap()) // lenient parser never fails
+    }
+
+    fn _process(&mut self, text: &str, finish: bool) {
+        match self.state {
+            Some((ref mut tokens, _)) => tokens.push(text),
+            None => match self.pending.push(text, finish) {
+                Some(tokens) => self.state = Some(Parser::_builder(tokens)),
+                None => return,
+            },
+        }
+
+        if let Some((ref mut tokens, ref mut builder)) = self.state {
+            tokens.tokens(finish, |token| builder.token(token)).unwrap(); // lenient parser never fails
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Parser {
+        Parser::new()
+    }
+}
@@ -0,0 +1,418 @@
+use std::cell::Cell;
+use std::{cmp, mem};
+
+use super::html::{RAW, RCDATA};
+
+/// A token of HTML (or XML) source, see `Tokenizer`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token<'a> {
+    /// The token kind with its content.
+    pub kind: TokenKind<'a>,
+    /// The source of the token.
+    pub source: &'a str,
+    /// The byte offset of the token in the source.
+    pub offset: usize,
+}
+
+/// The token kinds of `Token`.
+///
+/// Names keep their case and character references are not decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind<'a> {
+    /// Text (including a stray `<`).
+    Text(&'a str),
+    /// The content of an HTML raw text element (`script`, `style`, `title` and `textarea`).
+    RawText(&'a str),
+    /// A start tag with the name and attributes (`None` for an attribute without value).
+    StartTag {
+        /// The tag name.
+        name: &'a str,
+        /// The attributes in source order.
+        attrs: Vec<(&'a str, Option<&'a str>)>,
+        /// Whether the tag ends with `/>`.
+        self_closing: bool,
+    },
+    /// An end tag with the name.
+    EndTag(&'a str),
+    /// A comment.
+    Comment(&'a str),
+    /// A DOCTYPE declaration (without the `DOCTYPE` keyword).
+    Doctype(&'a str),
+    /// A CDATA section.
+    CData(&'a str),
+    /// A processing instruction.
+    ProcessingInstruction(&'a str),
+}
+
+/// Iterator over the tokens of HTML (or XML) source in one pass.
+///
+/// ```
+/// use victoria_dom::{Tokenizer, TokenKind};
+/// let kinds: Vec<_> = Tokenizer::new("<p class=a>1 < 2<br/><!-- c --></p>").map(|x| x.kind).collect();
+/// assert_eq!(kinds, [
+///     TokenKind::StartTag { name: "p", attrs: vec![("class", Some("a"))], self_closing: false },
+///     TokenKind::Text("1 <"),
+///     TokenKind::Text(" 2"),
+///     TokenKind::StartTag { name: "br", attrs: vec![], self_closing: true },
+///     TokenKind::Comment(" c "),
+///     TokenKind::EndTag("p"),
+/// ]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Tokenizer<'a> {
+    input: &'a str,
+    pos: usize,
+    xml: bool,
+    raw: Option<String>, // raw text element, whose content comes next
+    next: Option<Token<'a>>, // markup found after text
+    partial: bool, // more input follows, stop at tokens that could continue
+    eof: Cell<bool>, // the end of the input has been reached while scanning the last token
+    resume: usize, // the first token has been scanned up to here without finding its end (partial input)
+    missing: [Cell<usize>; 4], // the end of a DOCTYPE, comment, CDATA or processing instruction doesn't follow these offsets
+}
+
+// Markup with ends that can be missing (indexes of `Tokenizer::missing`)
+const DOCTYPE: usize = 0;
+const COMMENT: usize = 1;
+const CDATA: usize = 2;
+const PI: usize = 3;
+
+impl<'a> Tokenizer<'a> {
+    /// Construct a new `Tokenizer` for HTML.
+    pub fn new(html: &'a str) -> Tokenizer<'a> {
+        Tokenizer::_new(html, false)
+    }
+
+    /// Construct a new `Tokenizer` for XML, which has no raw text elements.
+    pub fn new_xml(xml: &'a str) -> Tokenizer<'a> {
+        Tokenizer::_new(xml, true)
+    }
+
+    fn _new(input: &'a str, xml: bool) -> Tokenizer<'a> {
+        let missing = [Cell::new(usize::MAX), Cell::new(usize::MAX), Cell::new(usize::MAX), Cell::new(usize::MAX)];
+        Tokenizer { input, pos: 0, xml, raw: None, next: None, partial: false, eof: Cell::new(false), resume: 0, missing }
+    }
+
+    // The last token could be different with more input
+    fn _is_incomplete(&self) -> bool {
+        self.partial && self.eof.get()
+    }
+
+    fn _starts_with(&self, text: &str, prefix: &str) -> bool {
+        if text.len() < prefix.len() && _starts_with_ignore_case(prefix, text) { self.eof.set(true); }
+        _starts_with_ignore_case(text, prefix)
+    }
+
+    fn _find(&self, text: &str, pattern: &str) -> Option<usize> {
+        let result = text.find(pattern);
+        if result.is_none() { self.eof.set(true); }
+        result
+    }
+
+    // Search the end of the markup from the offset `from` (relative to it), an end that hasn't been found
+    // isn't searched for again, so unterminated markup is scanned only once
+    fn _find_end<F: Fn(&str) -> Option<usize>>(&self, markup: usize, from: usize, find: F) -> Option<usize> {
+        let missing = &self.missing[markup];
+        let result = if from < missing.get() { find(&self.input[from ..]) } else { None };
+        if result.is_none() {
+            self.eof.set(true);
+            missing.set(cmp::min(missing.get(), from));
+        }
+        result
+    }
+
+    fn _token(&self, kind: TokenKind<'a>, start: usize, end: usize) -> Token<'a> {
+        Token { kind, source: &self.input[start .. end], offset: start }
+    }
+
+    fn _emit(&mut self, token: Token<'a>) -> Token<'a> {
+        self.pos = token.offset + token.source.len();
+        if let TokenKind::StartTag { name, .. } = token.kind {
+            let name = name.to_lowercase();
+            if !self.xml && (RAW.contains(name.as_str()) || RCDATA.contains(name.as_str())) { self.raw = Some(name); }
+        }
+        token
+    }
+
+    // Content up to the end tag of a raw text element, searched from `resume` on
+    fn _raw_text(&mut self, name: &str, resume: usize) -> Option<Token<'a>> {
+        let start = self.pos;
+        let mut offset = cmp::max(start, resume);
+        self.resume = self.input.len();
+        while let Some(x) = self._find(&self.input[offset ..], "<") {
+            let lt = offset + x;
+            if _is_raw_end_tag(&self.input[lt ..], name) {
+                if lt == start { return None; } // empty
+                self.pos = lt;
+                return Some(self._token(TokenKind::RawText(&self.input[start .. lt]), start, lt));
+            }
+            // Only the last "<" can be the start of an incomplete end tag
+            self.resume = lt;
+            offset = lt + 1;
+        }
+        None
+    }
+
+    // DOCTYPE, comment, CDATA, processing instruction or tag starting with "<"
+    fn _markup(&self, start: usize) -> Option<Token<'a>> {
+        let rest = &self.input[start ..];
+
+        // DOCTYPE
+        if self._starts_with(rest, "<!DOCTYPE") {
+            let content = &rest[9 ..];
+            let ws = content.len() - content.trim_start().len();
+            if ws == content.len() { self.eof.set(true); }
+            let word = content[ws ..].chars().next().is_some_and(_is_word);
+            if ws > 0 && word {
+                let first = ws + content[ws ..].chars().next().unwrap().len_utf8();
+                if let Some(x) = self._find_end(DOCTYPE, start + 9 + first, |x| x.find('>')) {
+                    let end = 9 + first + x;
+                    return Some(self._token(TokenKind::Doctype(&rest[9 .. end]), start, start + end + 1));
+                }
+            }
+        }
+
+        // Comment
+        if self._starts_with(rest, "<!--") {
+            if let Some(x) = self._find_end(COMMENT, start + 4, _comment_end) {
+                let dashes = 4 + x;
+                let end = _skip_whitespace(rest, dashes + 2) + 1;
+                return Some(self._token(TokenKind::Comment(&rest[4 .. dashes]), start, start + end));
+            }
+        }
+
+        // CDATA
+        if self._starts_with(rest, "<![CDATA[") {
+            if let Some(x) = self._find_end(CDATA, start + 9, |x| x.find("]]>")) {
+                return Some(self._token(TokenKind::CData(&rest[9 .. 9 + x]), start, start + 9 + x + 3));
+            }
+        }
+
+        // Processing instruction
+        if self._starts_with(rest, "<?") {
+            if let Some(x) = self._find_end(PI, start + 2, |x| x.find("?>")) {
+                return Some(self._token(TokenKind::ProcessingInstruction(&rest[2 .. 2 + x]), start, start + 2 + x + 2));
+            }
+        }
+
+        // Tag
+        let inner_start = 1 + (rest[1 ..].len() - rest[1 ..].trim_start().len());
+        let inner_end = _tag_end(rest, inner_start, &self.eof)?;
+        let inner = &rest[inner_start .. inner_end];
+        let end = start + inner_end + 1;
+
+        // End tag
+        if inner.starts_with('/') {
+            return Some(self._token(TokenKind::EndTag(inner.trim_start_matches('/').trim()), start, end));
+        }
+
+        // Start tag with attributes
+        let name_end = inner.find(|c: char| c.is_whitespace() || c == '/').unwrap_or(inner.len());
+        let mut attrs = Vec::new();
+        let mut self_closing = false;
+        let mut offset = name_end;
+        while offset < inner.len() {
+            match _attr(inner, offset, &Cell::new(false)) {
+                Some((key, value, next)) => {
+                    if key == "/" { self_closing = true } else { attrs.push((key, value)) }
+                    offset = next;
+                },
+                None => offset += inner[offset ..].chars().next().unwrap().len_utf8(),
+            }
+        }
+
+        Some(self._token(TokenKind::StartTag { name: &inner[.. name_end], attrs, self_closing }, start, end))
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        let mut resume = mem::replace(&mut self.resume, 0);
+        if let Some(token) = self.next.take() { return Some(self._emit(token)); }
+        if self.pos >= self.input.len() { return None; }
+        self.eof.set(false);
+
+        // Content of raw text elements
+        if let Some(name) = self.raw.take() {
+            let token = self._raw_text(&name, resume);
+            if self._is_incomplete() {
+                self.raw = Some(name);
+                return None;
+            }
+            self.resume = 0;
+            if token.is_some() { return token; }
+            resume = 0; // no end tag, the content is text
+        }
+
+        let start = self.pos;
+        if self.input[start ..].starts_with('<') {
+            let token = self._markup(start);
+            if self._is_incomplete() { return None; }
+            if let Some(token) = token { return Some(self._emit(token)); }
+
+            // Runaway "<"
+            self.pos = start + 1;
+            return Some(self._token(TokenKind::Text("<"), start, start + 1));
+        }
+
+        // Text (with a runaway "<")
+        let from = cmp::max(start, resume);
+        let end = match self._find(&self.input[from ..], "<") {
+            Some(x) => {
+                let lt = from + x;
+                let next = self._markup(lt);
+                if self._is_incomplete() {
+                    self.resume = lt;
+                    return None;
+                }
+                self.next = next;
+                if self.next.is_some() { lt } else { lt + 1 }
+            },
+            None if self._is_incomplete() => {
+                self.resume = self.input.len();
+                return None;
+            },
+            None => self.input.len(),
+        };
+        self.pos = end;
+        Some(self._token(TokenKind::Text(&self.input[start .. end]), start, end))
+    }
+}
+
+fn _is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn _starts_with_ignore_case(text: &str, prefix: &str) -> bool {
+    text.len() >= prefix.len() && text.as_bytes()[.. prefix.len()].eq_ignore_ascii_case(prefix.as_bytes())
+}
+
+// The position of "--" ending a comment (followed by optional whitespace and ">")
+fn _comment_end(text: &str) -> Option<usize> {
+    let mut offset = 0;
+    while let Some(x) = text[offset ..].find("--") {
+        let dashes = offset + x;
+        if text[_skip_whitespace(text, dashes + 2) ..].starts_with('>') { return Some(dashes); }
+        offset = dashes + 1;
+    }
+    None
+}
+
+fn _skip_whitespace(text: &str, offset: usize) -> usize {
+    text.len() - text[offset ..].trim_start().len()
+}
+
+// The position of ">" ending a tag, whose name starts at `offset`
+fn _tag_end(text: &str, offset: usize, eof: &Cell<bool>) -> Option<usize> {
+    let name_end = offset + text[offset ..].find(|c: char| c == '<' || c == '>' || c.is_whitespace()).unwrap_or(text.len() - offset);
+    if name_end == text.len() { eof.set(true); }
+    if name_end == offset { return None; }
+
+    let mut offset = _skip_whitespace(text, name_end);
+    loop {
+        if text[offset ..].starts_with('>') { return Some(offset); }
+        offset = _attr(text, offset, eof)?.2;
+    }
+}
+
+// Attribute key and value at `offset`, followed by the position after trailing whitespace
+fn _attr<'a>(text: &'a str, offset: usize, eof: &Cell<bool>) -> Option<(&'a str, Option<&'a str>, usize)> {
+    let rest = &text[offset ..];
+    let key_len = match rest.find(|c: char| c == '<' || c == '>' || c == '=' || c == '/' || c.is_whitespace()) {
+        Some(0) if rest.starts_with('/') => 1,
+        Some(0) => return None,
+        Some(x) => x,
+        None if rest.is_empty() => {
+            eof.set(true);
+            return None;
+        },
+        None => rest.len(),
+    };
+    let key = &rest[.. key_len];
+    let mut end = offset + key_len;
+
+    // Value
+    let mut value = None;
+    let equals = _skip_whitespace(text, end);
+    if text[equals ..].starts_with('=') {
+        let start = _skip_whitespace(text, equals + 1);
+        let rest = &text[start ..];
+        let quote = rest.chars().next().filter(|&c| c == '"' || c == '\'');
+        let quoted = quote.and_then(|quote| rest[1 ..].find(quote).map(|x| (&rest[1 .. 1 + x], start + x + 2)));
+        if quote.is_some() && quoted.is_none() { eof.set(true); }
+        let (x, next) = match quoted {
+            Some(x) => x,
+            None => {
+                let len = rest.find(|c: char| c == '>' || c.is_whitespace()).unwrap_or(rest.len());
+                (&rest[.. len], start + len)
+            },
+        };
+        value = Some(x);
+        end = next;
+    }
+
+    Some((key, value, _skip_whitespace(text, end)))
+}
+
+// Check if the text starts with the end tag of the raw text element
+fn _is_raw_end_tag(text: &str, name: &str) -> bool {
+    let offset = _skip_whitespace(text, 1);
+    if !text[offset ..].starts_with('/') { return false; }
+    let offset = _skip_whitespace(text, offset + 1);
+    if !_starts_with_ignore_case(&text[offset ..], name) { return false; }
+    let offset = _skip_whitespace(text, offset + name.len());
+    text[offset ..].starts_with('>')
+}
+
+// Tokenizer for input arriving in chunks, tokens are taken as soon as they are complete
+#[derive(Debug)]
+pub struct TokenBuffer {
+    buffer: String,
+    offset: usize, // of the buffer in the input
+    xml: bool,
+    raw: Option<String>,
+    resume: usize, // the incomplete first token has been scanned up to here
+    scanned: usize, // length of the buffer at the last scan
+}
+
+impl TokenBuffer {
+    pub fn new(xml: bool) -> TokenBuffer {
+        TokenBuffer { buffer: String::new(), offset: 0, xml, raw: None, resume: 0, scanned: 0 }
+    }
+
+    pub fn push(&mut self, chunk: &str) {
+        self.buffer.push_str(chunk);
+    }
+
+    pub fn is_xml(&self) -> bool {
+        self.xml
+    }
+
+    // Pass the complete tokens (all remaining ones at the end of the input) to the callback,
+    // with offsets in the whole input
+    pub fn tokens<E, F: FnMut(Token) -> Result<(), E>>(&mut self, finish: bool, mut callback: F) -> Result<(), E> {
+        // Scanning continues where it stopped, parts that have to be scanned again (like an unterminated tag)
+        // wait for at least as much new input, so the whole input is scanned in linear time
+        if !finish && self.buffer.len() - self.scanned < self.scanned - self.resume { return Ok(()); }
+
+        let mut tokenizer = Tokenizer::_new(&self.buffer, self.xml);
+        tokenizer.partial = !finish;
+        tokenizer.raw = self.raw.take();
+        tokenizer.resume = self.resume;
+
+        for mut token in tokenizer.by_ref() {
+            token.offset += self.offset;
+            callback(token)?;
+        }
+
+        let consumed = tokenizer.pos;
+        self.raw = tokenizer.raw.take();
+        self.resume = tokenizer.resume.saturating_sub(consumed);
+        self.buffer.drain(.. consumed);
+        self.offset += consumed;
+        self.scanned = self.buffer.len();
+        Ok(())
+    }
+}
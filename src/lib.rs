@@ -1,4 +1,4 @@
-#![doc(html_root_url = "https://docs.rs/victoria-dom/0.1.2")]
+#![doc(html_root_url = "https://docs.rs/victoria-dom/0.2.0")]
 #![deny(missing_docs)]
 #![deny(warnings)]
 #![deny(missing_debug_implementations)]
@@ -8,6 +8,7 @@
 //! The project has been inspired by [Mojo::DOM](https://metacpan.org/pod/Mojo::DOM).
 //!
 //! It will even try to interpret broken HTML, so you should not use it for validation.
+//! XML documents are supported as well, see `DOM::new_xml`.
 //!
 //! # Examples
 //!
@@ -38,6 +39,7 @@
 //! * `E:root` An `E` element, root of the document.
 //! * `E:nth-child(n)` An `E` element, the `n-th` child of its parent.
 //! * `E:nth-last-child(n)` An `E` element, the `n-th` child of its parent, counting from the last one.
+//! * `E:nth-child(n of s)` An `E` element, the `n-th` child of its parent among the children matching selector `s` (also for `:nth-last-child`).
 //! * `E:nth-of-type(n)` An `E` element, the `n-th` sibling of its type.
 //! * `E:nth-last-of-type(n)` An `E` element, the `n-th` sibling of its type, counting from the last one.
 //! * `E:first-child` An `E` element, first child of its parent.
@@ -50,19 +52,27 @@
 //! * `E:checked` A user interface element `E` which is checked (for instance a radio-button or checkbox).
 //! * `E.warning` An `E` element whose class is `warning`.
 //! * `E#myid` An `E` element with ID equal to `myid`.
-//! * `E:not(s)` An `E` element that does not match simple selector `s`.
+//! * `E:not(s1, s2)` An `E` element that does not match selector `s1` or `s2` (like `E:not(div > p)`).
+//! * `E:is(s1, s2)` An `E` element that matches selector `s1` or `s2` (`:where` is an alias).
+//! * `E:has(> s1, + s2)` An `E` element with a child matching `s1` or an `E` element immediately followed by an `s2` element (relative selectors, descendants by default).
 //! * `E F` An `F` element descendant of an `E` element.
 //! * `E > F` An `F` element child of an `E` element.
 //! * `E + F` An `F` element immediately preceded by an `E` element.
 //! * `E ~ F` An `F` element preceded by an `E` element.
 //! * `E, F, G` Elements of type `E`, `F` and `G`.
 //! * `E[foo=bar][bar=baz]` An `E` element whose attributes match all following attribute selectors.
+//! * `ns|E` An `E` element in namespace `ns` (see `Selector::parse_with_namespaces`).
+//! * `*|E` An `E` element in any namespace.
+//! * `|E` An `E` element without a namespace.
+//! * `E[ns|foo]` An `E` element with a `foo` attribute in namespace `ns`.
 
+extern crate encoding_rs;
 #[macro_use] extern crate lazy_static;
 #[macro_use] extern crate maplit;
 extern crate regex;
 
-pub use dom::DOM;
+pub use dom::{DOM, AttrOrder, Attrs, Elements, Entities, Escape, Event, EventParser, EventReader, Fragment, NodeSpan, NodeType, ParseError, ParseErrorKind, Parser, Position};
+pub use dom::{Quote, RenderOptions, Selector, SelectorError, SelectorErrorKind, Span, TextKind, Token, TokenKind, Tokenizer};
 
 mod dom;
 mod util;
@@ -1,20 +1,62 @@
 use std::collections::HashMap;
-use std::{char, u32};
+use std::char;
 use std::cmp;
+use std::str;
 
 use regex::{Regex, Captures};
 
 lazy_static! {
     static ref ENTITY_RE: Regex = Regex::new(r#"&(?:\#((?:[0-9]{1,7}|x[0-9a-fA-F]{1,6}));|(\w+[;=]?))"#).unwrap();
+    // Numeric references of any length, to report the ones the lenient parser leaves undecoded
+    static ref STRICT_ENTITY_RE: Regex = Regex::new(r#"&(?:\#((?:[0-9]+|x[0-9a-fA-F]+));|(\w+[;=]?))"#).unwrap();
 }
 
-pub fn xml_escape(text: &str) -> String {
-    let mut text = text.to_owned();
-    text = text.replace("&", "&amp;");
-    text = text.replace("<", "&lt;");
-    text = text.replace(">", "&gt;");
-    text = text.replace("\"", "&quot;");
-    text = text.replace("'", "&#39;");
+// Escape the characters with named (only XML ones, "'" has no name in HTML 4) or numeric character references
+pub fn xml_escape<F: Fn(char) -> bool>(text: &str, is_escaped: F, numeric: bool) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            _ if !is_escaped(c) => result.push(c),
+            '&' if !numeric => result += "&amp;",
+            '<' if !numeric => result += "&lt;",
+            '>' if !numeric => result += "&gt;",
+            '"' if !numeric => result += "&quot;",
+            _ => result += &format!("&#{};", c as u32),
+        }
+    }
+    result
+}
+
+// Decode the UTF-8 input (invalid sequences are replaced with U+FFFD), an incomplete sequence
+// at the end is left in the buffer for the next chunk
+pub fn utf8_decode(bytes: &mut Vec<u8>) -> String {
+    let mut text = String::new();
+    let mut start = 0;
+    loop {
+        match str::from_utf8(&bytes[start ..]) {
+            Ok(x) => {
+                text.push_str(x);
+                start = bytes.len();
+                break;
+            },
+            Err(e) => {
+                let valid = start + e.valid_up_to();
+                text.push_str(str::from_utf8(&bytes[start .. valid]).unwrap());
+                match e.error_len() {
+                    Some(len) => {
+                        text.push('\u{FFFD}');
+                        start = valid + len;
+                    },
+                    // Incomplete sequence at the end of the chunk
+                    None => {
+                        start = valid;
+                        break;
+                    },
+                }
+            },
+        }
+    }
+    bytes.drain(.. start);
     text
 }
 
@@ -26,6 +68,19 @@ pub fn html_attr_unescape(text: &str) -> String {
     _html_unescape(text, true)
 }
 
+// Find the first character reference that can't be decoded (unterminated unknown references are allowed)
+pub fn find_bad_entity(text: &str, is_attr: bool) -> Option<&str> {
+    STRICT_ENTITY_RE.captures_iter(text).filter(|caps| {
+        match caps.get(1) {
+            Some(point) => _code_point(point.as_str()).is_none(),
+            None => {
+                let name = caps.get(2).unwrap().as_str();
+                name.ends_with(';') && _decode_name(name, is_attr) == "&".to_owned() + name
+            },
+        }
+    }).map(|caps| caps.get(0).unwrap().as_str()).next()
+}
+
 fn _html_unescape(text: &str, is_attr: bool) -> String {
     ENTITY_RE.replace_all(text, |caps: &Captures| {
         caps.get(1)
@@ -36,13 +91,21 @@ fn _html_unescape(text: &str, is_attr: bool) -> String {
 
 fn _decode_point(point: &str) -> String {
     // Code point
-    (if point.starts_with("x") { u32::from_str_radix(&point[1..], 16) } else { u32::from_str_radix(point, 10) })
+    (if let Some(hex) = point.strip_prefix('x') { u32::from_str_radix(hex, 16) } else { point.parse::<u32>() })
         .ok()
         .and_then(char::from_u32)
         .map(|c| c.to_string())
         .unwrap_or(point.to_owned())
 }
 
+// Valid code point (strict mode), `None` if it is out of range, a surrogate or NUL
+fn _code_point(point: &str) -> Option<char> {
+    (if let Some(hex) = point.strip_prefix('x') { u32::from_str_radix(hex, 16) } else { point.parse::<u32>() })
+        .ok()
+        .and_then(char::from_u32)
+        .filter(|&c| c != '\0')
+}
+
 pub fn _decode_name(name: &str, is_attr: bool) -> String {
     lazy_static! {
         static ref _ALPHANUMEQ_RE: Regex = Regex::new("[A-Za-z0-9=]").unwrap();
@@ -1,6 +1,8 @@
+#[macro_use] extern crate lazy_static;
 extern crate victoria_dom;
 
-use victoria_dom::DOM;
+use victoria_dom::{DOM, AttrOrder, Attrs, Entities, Escape, NodeType, ParseErrorKind, Quote, RenderOptions, Selector, SelectorErrorKind, Span, TextKind};
+use victoria_dom::{Event, EventParser, EventReader, Parser, TokenKind, Tokenizer};
 
 #[test]
 fn empty_vals() {
@@ -23,7 +25,7 @@ fn basic1() {
     let dom = DOM::new(r#"<div><div FOO="0" id="a">A</div><div id="b" myAttr>B</div></div>"#);
     assert_eq!(dom.at("#b").unwrap().text(), "B");
     assert_eq!(dom.find("div[id]").iter().map(|x| x.text()).collect::<Vec<_>>(), ["A", "B"]);
-    assert_eq!(dom.at("#a").unwrap().attr("foo"), Some("0"));
+    assert_eq!(dom.at("#a").unwrap().attr("foo").as_deref(), Some("0"));
     assert!(dom.at("#b").unwrap().attrs().contains_key("myattr"));
     assert_eq!(dom.find("[id]").iter().map(|x| x.attr("id").unwrap()).collect::<Vec<_>>(), ["a", "b"]);
     assert_eq!(dom.to_string(), r#"<div><div foo="0" id="a">A</div><div id="b" myattr>B</div></div>"#);
@@ -38,10 +40,10 @@ fn basic2() {
 <div><div>test2</div></div>
 <body>
     "#);
-    assert_eq!(dom.find("body > div").get(0).unwrap().text(), "test1");        // right text
+    assert_eq!(dom.find("body > div").first().unwrap().text(), "test1");        // right text
     assert_eq!(dom.find("body > div").get(1).unwrap().text(), "");             // no content
     assert_eq!(dom.find("body > div").len(), 2);                               // right number of elements
-    assert_eq!(dom.find("body > div > div").get(0).unwrap().text(), "test2");  // right text
+    assert_eq!(dom.find("body > div > div").first().unwrap().text(), "test2");  // right text
     assert_eq!(dom.find("body > div > div").len(), 1);                         // right number of elements
 }
 
@@ -146,7 +148,7 @@ fn deep_nesting() {
 
     let p = dom.find("body > #container > div p[id]");
     assert_eq!(p.len(), 1);
-    assert_eq!(p.get(0).unwrap().attr("id").unwrap(), "foo");
+    assert_eq!(p.first().unwrap().attr("id").unwrap(), "foo");
 
     assert_eq!(
         dom.find("div").iter().map(|x| x.attr("id").unwrap()).collect::<Vec<_>>(),
@@ -427,22 +429,22 @@ fn pseudo_classes() {
 </form>
     "#);
     assert_eq!(dom.find(":root").len(), 1);
-    assert_eq!(dom.find(":root").get(0).unwrap().tag(), Some("form"));
-    assert_eq!(dom.find("*:root").get(0).unwrap().tag(), Some("form"));
-    assert_eq!(dom.find("form:root").get(0).unwrap().tag(), Some("form"));
+    assert_eq!(dom.find(":root").first().unwrap().tag(), Some("form"));
+    assert_eq!(dom.find("*:root").first().unwrap().tag(), Some("form"));
+    assert_eq!(dom.find("form:root").first().unwrap().tag(), Some("form"));
     assert_eq!(dom.find(":checked").len(), 4);
-    assert_eq!(dom.find(":checked").get(0).unwrap().attr("name").unwrap(), "groovy");
-    assert_eq!(dom.find("option:checked").get(0).unwrap().attr("value").unwrap(), "e");
+    assert_eq!(dom.find(":checked").first().unwrap().attr("name").unwrap(), "groovy");
+    assert_eq!(dom.find("option:checked").first().unwrap().attr("value").unwrap(), "e");
     assert_eq!(dom.find(":checked").get(1).unwrap().text(), "E");
     assert_eq!(dom.find("*:checked").get(1).unwrap().text(), "E");
     assert_eq!(dom.find(":checked").get(2).unwrap().text(), "H");
     assert_eq!(dom.find(":checked").get(3).unwrap().attr("name").unwrap(), "I");
     assert_eq!(dom.find("option[selected]").len(), 2);
-    assert_eq!(dom.find("option[selected]").get(0).unwrap().attr("value").unwrap(), "e");
+    assert_eq!(dom.find("option[selected]").first().unwrap().attr("value").unwrap(), "e");
     assert_eq!(dom.find("option[selected]").get(1).unwrap().text(), "H");
-    assert_eq!(dom.find(":checked[value=\"e\"]").get(0).unwrap().text(), "E");
-    assert_eq!(dom.find("*:checked[value=\"e\"]").get(0).unwrap().text(), "E");
-    assert_eq!(dom.find("option:checked[value=\"e\"]").get(0).unwrap().text(), "E");
+    assert_eq!(dom.find(":checked[value=\"e\"]").first().unwrap().text(), "E");
+    assert_eq!(dom.find("*:checked[value=\"e\"]").first().unwrap().text(), "E");
+    assert_eq!(dom.find("option:checked[value=\"e\"]").first().unwrap().text(), "E");
     assert_eq!(dom.at("optgroup option:checked[value=\"e\"]").unwrap().text(), "E");
     assert_eq!(dom.at("select option:checked[value=\"e\"]").unwrap().text(), "E");
     assert_eq!(dom.at("select :checked[value=\"e\"]").unwrap().text(), "E");
@@ -450,8 +452,8 @@ fn pseudo_classes() {
     assert_eq!(dom.at("select *:checked[value=\"e\"]").unwrap().text(), "E");
     assert_eq!(dom.at("optgroup > *:checked[value=\"e\"]").unwrap().text(), "E");
     assert_eq!(dom.find(":checked[value=\"e\"]").len(), 1);
-    assert_eq!(dom.find(":empty").get(0).unwrap().attr("name").unwrap(), "user");
-    assert_eq!(dom.find("input:empty").get(0).unwrap().attr("name").unwrap(), "user");
+    assert_eq!(dom.find(":empty").first().unwrap().attr("name").unwrap(), "user");
+    assert_eq!(dom.find("input:empty").first().unwrap().attr("name").unwrap(), "user");
     assert_eq!(dom.at(":empty[type^=\"ch\"]").unwrap().attr("name").unwrap(), "groovy");
     assert_eq!(dom.at("p").unwrap().attr("id").unwrap(), "content");
     assert_eq!(dom.at("p:empty").unwrap().attr("id").unwrap(), "no_content");
@@ -472,13 +474,13 @@ fn pseudo_classes() {
     assert_eq!(dom.find("li:nth-child(odd)").iter().map(|x| x.text()).collect::<Vec<_>>(), ["A", "C", "E", "G"]);
     assert_eq!(dom.find("li:NTH-CHILD(ODD)").iter().map(|x| x.text()).collect::<Vec<_>>(), ["A", "C", "E", "G"]);
     assert_eq!(dom.find("li:nth-last-child(odd)").iter().map(|x| x.text()).collect::<Vec<_>>(), ["B", "D", "F", "H"]);
-    assert_eq!(dom.find(":nth-child(odd)").get(0).unwrap().tag().unwrap(), "ul");
+    assert_eq!(dom.find(":nth-child(odd)").first().unwrap().tag().unwrap(), "ul");
     assert_eq!(dom.find(":nth-child(odd)").get(1).unwrap().text(), "A");
-    assert_eq!(dom.find(":nth-child(1)").get(0).unwrap().tag().unwrap(), "ul");
+    assert_eq!(dom.find(":nth-child(1)").first().unwrap().tag().unwrap(), "ul");
     assert_eq!(dom.find(":nth-child(1)").get(1).unwrap().text(), "A");
-    assert_eq!(dom.find(":nth-last-child(odd)").get(0).unwrap().tag().unwrap(), "ul");
+    assert_eq!(dom.find(":nth-last-child(odd)").first().unwrap().tag().unwrap(), "ul");
     assert_eq!(dom.find(":nth-last-child(odd)").last().unwrap().text(), "H");
-    assert_eq!(dom.find(":nth-last-child(1)").get(0).unwrap().tag().unwrap(), "ul");
+    assert_eq!(dom.find(":nth-last-child(1)").first().unwrap().tag().unwrap(), "ul");
     assert_eq!(dom.find(":nth-last-child(1)").get(1).unwrap().text(), "H");
     assert_eq!(dom.find("li:nth-child(2n+1)").iter().map(|x| x.text()).collect::<Vec<_>>(), ["A", "C", "E", "G"]);
     assert_eq!(dom.find("li:nth-child(2n + 1)").iter().map(|x| x.text()).collect::<Vec<_>>(), ["A", "C", "E", "G"]);
@@ -575,8 +577,1063 @@ fn pseudo_classes() {
     assert_eq!(dom.find("ul :nth-child(-n+3):not(*[class$=\"♥\"])").iter().map(|x| x.text()).collect::<Vec<_>>(), ["A", "B"]);
     assert_eq!(dom.find("ul :nth-child(-n+3):not(:nth-child(-n+2))").iter().map(|x| x.text()).collect::<Vec<_>>(), ["C"]);
     assert_eq!(dom.find("ul :nth-child(-n+3):not(:nth-child(1)):not(:nth-child(2))").iter().map(|x| x.text()).collect::<Vec<_>>(), ["C"]);
+    assert_eq!(dom.find("ul :nth-child(-n+3):not(ul > p)").iter().map(|x| x.text()).collect::<Vec<_>>(), ["A", "C"]);
+    assert_eq!(dom.find("ul :nth-child(-n+3):not(ul li)").iter().map(|x| x.text()).collect::<Vec<_>>(), ["B"]);
+    assert_eq!(dom.find("ul :nth-child(-n+3):not(p + li)").iter().map(|x| x.text()).collect::<Vec<_>>(), ["A", "B"]);
+    assert_eq!(dom.find("ul :nth-child(-n+3):not(li ~ *)").iter().map(|x| x.text()).collect::<Vec<_>>(), ["A"]);
+    assert_eq!(dom.find("ul :nth-child(-n+3):not(p, li ~ .test)").iter().map(|x| x.text()).collect::<Vec<_>>(), ["A"]);
+    assert_eq!(dom.find("ul :nth-child(-n+3):not(div li)").iter().map(|x| x.text()).collect::<Vec<_>>(), ["A", "B", "C"]);
+    assert_eq!(dom.at("ul").unwrap().find("li:not(ul > li)").len(), 0);
+    assert_eq!(dom.find(".☃:not(a + div)").iter().map(|x| x.text()).collect::<Vec<_>>(), ["J"]);
+    assert_eq!(dom.find("div:not(div .☃)").len(), 2);
     assert_eq!(dom.find(":only-child").iter().map(|x| x.text()).collect::<Vec<_>>(), ["J"]);
     assert_eq!(dom.find("div :only-of-type").iter().map(|x| x.text()).collect::<Vec<_>>(), ["J", "K"]);
     assert_eq!(dom.find("div:only-child").iter().map(|x| x.text()).collect::<Vec<_>>(), ["J"]);
     assert_eq!(dom.find("div div:only-of-type").iter().map(|x| x.text()).collect::<Vec<_>>(), ["J", "K"]);
 }
+
+#[test]
+fn manipulation() {
+    // Append and prepend
+    let dom = DOM::new("<div><p>A</p><p id=\"b\">B</p></div>");
+    dom.at("#b").unwrap().append("<p>C</p>").prepend("<p>A2</p>");
+    assert_eq!(dom.to_string(), "<div><p>A</p><p>A2</p><p id=\"b\">B</p><p>C</p></div>");
+    dom.at("div").unwrap().append_content("<p>D</p>").prepend_content("<h1>Test</h1>");
+    assert_eq!(dom.at("div").unwrap().childs(None).iter().map(|x| x.text()).collect::<Vec<_>>(), ["Test", "A", "A2", "B", "C", "D"]);
+    assert_eq!(dom.find("p:last-child").iter().map(|x| x.text()).collect::<Vec<_>>(), ["D"]);
+    dom.append("<footer>E</footer>"); // root has no siblings
+    assert_eq!(dom.find("footer").len(), 0);
+    dom.append_content("<footer>E</footer>");
+    assert_eq!(dom.to_string(), "<div><h1>Test</h1><p>A</p><p>A2</p><p id=\"b\">B</p><p>C</p><p>D</p></div><footer>E</footer>");
+
+    // Replace and remove
+    let dom = DOM::new("<div><p>A</p><img src=\"pixel.gif\"><p>B</p></div>");
+    assert_eq!(dom.at("img").unwrap().remove().tag(), Some("div"));
+    assert_eq!(dom.to_string(), "<div><p>A</p><p>B</p></div>");
+    assert_eq!(dom.at("p").unwrap().replace("<b>X</b><i>Y</i>").to_string(), "<div><b>X</b><i>Y</i><p>B</p></div>");
+    assert_eq!(dom.at("i").unwrap().next().unwrap().text(), "B");
+    assert_eq!(dom.at("i").unwrap().parent().unwrap().tag(), Some("div"));
+    assert_eq!(dom.replace("<p>New</p>").to_string(), "<p>New</p>");
+    assert_eq!(dom.find("p").len(), 1);
+    dom.at("p").unwrap().remove();
+    assert_eq!(dom.to_string(), "");
+
+    // Removed elements are detached, but still usable
+    let dom = DOM::new("<div><p><b>A</b></p></div>");
+    let p = dom.at("p").unwrap();
+    p.remove();
+    assert!(p.parent().is_none());
+    assert_eq!(p.at("b").unwrap().parent().unwrap().tag(), Some("p"));
+    assert_eq!(p.to_string(), "<p><b>A</b></p>");
+    assert_eq!(dom.to_string(), "<div></div>");
+
+    // Wrap and strip
+    let dom = DOM::new("<div><b>A</b></div>");
+    dom.at("b").unwrap().wrap("<p><i></i><u></u></p>");
+    assert_eq!(dom.to_string(), "<div><p><i><b>A</b></i><u></u></p></div>");
+    assert_eq!(dom.at("b").unwrap().ancestors(None).iter().map(|x| x.tag().unwrap()).collect::<Vec<_>>(), ["i", "p", "div"]);
+    dom.at("div").unwrap().wrap_content("<section>X</section>");
+    assert_eq!(dom.to_string(), "<div><section>X<p><i><b>A</b></i><u></u></p></section></div>");
+    dom.wrap_content("<body></body>");
+    assert_eq!(dom.to_string(), "<body><div><section>X<p><i><b>A</b></i><u></u></p></section></div></body>");
+    dom.at("b").unwrap().wrap("no tags");
+    assert_eq!(dom.at("b").unwrap().parent().unwrap().tag(), Some("i"));
+    assert_eq!(dom.at("p").unwrap().strip().tag(), Some("section"));
+    assert_eq!(dom.at("i").unwrap().strip().to_string(), "<section>X<b>A</b><u></u></section>");
+    assert_eq!(dom.at("b").unwrap().parent().unwrap().tag(), Some("section"));
+    assert_eq!(dom.at("section").unwrap().text_all(), "X A");
+
+    // DOM fragments are copied
+    let dom = DOM::new("<ul><li>A</li></ul>");
+    let other = DOM::new("<li>B</li><li>C</li>");
+    dom.at("ul").unwrap().append_content(&other);
+    dom.at("li").unwrap().prepend(&other.at("li:last-child").unwrap());
+    assert_eq!(dom.to_string(), "<ul><li>C</li><li>A</li><li>B</li><li>C</li></ul>");
+    assert_eq!(other.to_string(), "<li>B</li><li>C</li>");
+    dom.at("ul").unwrap().append_content(&dom.at("ul").unwrap());
+    assert_eq!(dom.find("ul").len(), 2);
+    assert_eq!(dom.find("ul > ul > li").iter().map(|x| x.text()).collect::<Vec<_>>(), ["C", "A", "B", "C"]);
+}
+
+#[test]
+fn attrs_manipulation() {
+    let dom = DOM::new(r#"<div><a href="/foo" class="link">Foo</a><a href="/bar">Bar</a><img src="pixel.gif"></div>"#);
+    dom.at("a[href=\"/foo\"]").unwrap().set_attr("href", "http://example.com/foo").set_attr("rel", None);
+    assert_eq!(dom.at("a[rel]").unwrap().text(), "Foo");
+    assert_eq!(dom.at("a[href^=\"http:\"]").unwrap().attr("href").unwrap(), "http://example.com/foo");
+    assert!(dom.at("a[href=\"/foo\"]").is_none());
+    assert_eq!(dom.at("a[rel]").unwrap().attr("rel"), None);
+    assert!(dom.at("a[rel]").unwrap().attrs().contains_key("rel"));
+    dom.at("a[rel]").unwrap().remove_attr("rel").remove_attr("missing");
+    assert!(dom.at("a[rel]").is_none());
+
+    dom.at("img").unwrap().set_attrs(vec![("src".to_owned(), Some("new.gif".to_owned())), ("hidden".to_owned(), None)]);
+    assert!(dom.at("img[src=\"new.gif\"][hidden]").unwrap().matches("img:not([src=\"pixel.gif\"])"));
+    assert_eq!(dom.at("img").unwrap().to_string(), r#"<img hidden src="new.gif">"#);
+    dom.at("img").unwrap().set_attrs(vec![("width", Some("1")), ("alt", Some("x"))]);
+    assert_eq!(dom.at("img").unwrap().attrs().keys().collect::<Vec<_>>(), ["src", "hidden", "width", "alt"]);
+    dom.at("img").unwrap().remove_attr("width").remove_attr("alt");
+
+    // Classes
+    let a = dom.at("a:last-of-type").unwrap();
+    assert!(!a.has_class("link"));
+    a.add_class("link").add_class("external").add_class("link");
+    assert!(a.has_class("link"));
+    assert_eq!(a.attr("class").unwrap(), "link external");
+    assert_eq!(dom.find(".link").len(), 2);
+    assert_eq!(dom.find("a.external").iter().map(|x| x.text()).collect::<Vec<_>>(), ["Bar"]);
+    a.toggle_class("link").toggle_class("active");
+    assert_eq!(a.attr("class").unwrap(), "external active");
+    assert!(!a.matches(".link"));
+    a.remove_class("external").remove_class("active");
+    assert!(a.attr("class").is_none());
+    assert_eq!(dom.to_string(), r#"<div><a class="link" href="http://example.com/foo">Foo</a><a href="/bar">Bar</a><img hidden src="new.gif"></div>"#);
+
+    // Not an element
+    assert!(!dom.has_class("link"));
+    dom.set_attr("id", "root").add_class("link");
+    assert!(dom.attrs().is_empty());
+
+    // Names are case-insensitive in HTML mode
+    let dom = DOM::new(r#"<a href="/foo">Foo</a>"#);
+    dom.at("a").unwrap().set_attr("HREF", "/bar").set_attrs(vec![("Title", Some("x"))]);
+    assert_eq!(dom.to_string(), r#"<a href="/bar" title="x">Foo</a>"#);
+    dom.at("a").unwrap().remove_attr("HREF").remove_attr("TITLE");
+    assert_eq!(dom.to_string(), "<a>Foo</a>");
+    let dom = DOM::new_xml(r#"<a href="/foo"/>"#);
+    dom.at("a").unwrap().set_attr("HREF", "/bar").remove_attr("Href");
+    assert_eq!(dom.to_string(), r#"<a HREF="/bar" href="/foo"/>"#);
+}
+
+#[test]
+fn strict_parsing() {
+    // Valid documents
+    let html = r#"<!DOCTYPE html>
+<html><head><title>A &amp; B</title></head>
+<body>
+  <ul><li>One<li>Two &copy; AT&T</ul>
+  <table><tr><td>1<td>2<tr><td>3</table>
+  <p>Text <br> <img src="a.png" alt="&lt;&gt;"><p>More text
+</body></html>"#;
+    assert_eq!(DOM::parse_strict(html).unwrap().to_string(), DOM::new(html).to_string());
+    assert!(DOM::parse_strict("").is_ok());
+    assert!(DOM::parse_strict("<br/><foo/><p>").is_ok());
+    assert!(DOM::parse_strict("<script>if (a < b) { x = \"&bogus;\"; }</script>").is_ok());
+    let html = "<select><option>a<option>b</select><label><select><optgroup label=x><option>c</select></label>";
+    assert_eq!(DOM::parse_strict(html).unwrap().to_string(), "<select><option>a</option><option>b</option></select><label><select><optgroup label=\"x\"><option>c</option></optgroup></select></label>");
+    assert_eq!(DOM::new(html).to_string(), "<select><option>a</option><option>b<label><select><optgroup label=\"x\"></optgroup></select></label></option><option>c</option></select>");
+    assert_eq!(DOM::new("<b><li>x</b>y").to_string(), "<b><li>xy</li></b>");
+
+    // Unclosed tag
+    let err = DOM::parse_strict("<div>\n  <span class=\"x\">Hello\n</div>").unwrap_err();
+    assert_eq!(err.kind, ParseErrorKind::UnclosedTag);
+    assert_eq!((err.line, err.column), (2, 3));
+    assert_eq!(err.token, "<span class=\"x\">");
+    assert_eq!(err.to_string(), "unclosed tag \"<span class=\\\"x\\\">\" at line 2, column 3");
+    let err = DOM::parse_strict("<div><p>Тест</p><b>Hello").unwrap_err();
+    assert_eq!((err.kind, err.line, err.column, err.token.as_str()), (ParseErrorKind::UnclosedTag, 1, 17, "<b>"));
+    let err = DOM::parse_strict("<div/>").unwrap_err();
+    assert_eq!((err.kind, err.token.as_str()), (ParseErrorKind::UnclosedTag, "<div/>"));
+    let err = DOM::parse_strict("<script>var x;").unwrap_err();
+    assert_eq!((err.kind, err.token.as_str()), (ParseErrorKind::UnclosedTag, "<script>"));
+    let err = DOM::parse_strict("<ul><li><b>A<li>B</ul>").unwrap_err();
+    assert_eq!((err.kind, err.line, err.column, err.token.as_str()), (ParseErrorKind::UnclosedTag, 1, 9, "<b>"));
+    let err = DOM::parse_strict("<div><p><b>x<div>y</div></div>").unwrap_err();
+    assert_eq!((err.kind, err.line, err.column, err.token.as_str()), (ParseErrorKind::UnclosedTag, 1, 9, "<b>"));
+
+    // Stray end tag
+    let err = DOM::parse_strict("<div>\n<p>Hello</b></p></div>").unwrap_err();
+    assert_eq!((err.kind, err.line, err.column, err.token.as_str()), (ParseErrorKind::StrayEndTag, 2, 9, "</b>"));
+    let err = DOM::parse_strict("<div></div></ div >").unwrap_err();
+    assert_eq!((err.kind, err.line, err.column, err.token.as_str()), (ParseErrorKind::StrayEndTag, 1, 12, "</ div >"));
+
+    // Duplicate attribute
+    let err = DOM::parse_strict("<div>\n<a href=\"/a\" HREF=\"/b\">A</a></div>").unwrap_err();
+    assert_eq!((err.kind, err.line, err.column, err.token.as_str()), (ParseErrorKind::DuplicateAttribute, 2, 14, "HREF"));
+
+    // Bad entity
+    let err = DOM::parse_strict("<p>Fish &amp; chips &bogus; </p>").unwrap_err();
+    assert_eq!((err.kind, err.line, err.column, err.token.as_str()), (ParseErrorKind::BadEntity, 1, 21, "&bogus;"));
+    let err = DOM::parse_strict("<a title=\"&#xD800;\"></a>").unwrap_err();
+    assert_eq!((err.kind, err.line, err.column, err.token.as_str()), (ParseErrorKind::BadEntity, 1, 11, "&#xD800;"));
+    let err = DOM::parse_strict("<title>&nope;</title>").unwrap_err();
+    assert_eq!((err.kind, err.token.as_str()), (ParseErrorKind::BadEntity, "&nope;"));
+    for entity in &["&#12345678;", "&#x110000;", "&#xdfff;", "&#0;", "&#000000000065;x&#99999999999999999999;"] {
+        let err = DOM::parse_strict(&format!("<p>{}</p>", entity)).unwrap_err();
+        assert_eq!(err.kind, ParseErrorKind::BadEntity);
+    }
+    let dom = DOM::new("<p>&#12345678; &#xD800; &#0; &#0000065;</p>");
+    assert_eq!(dom.at("p").unwrap().text(), "&#12345678; xD800 \0 A");
+    assert_eq!(dom.at("p").unwrap().content(), "&amp;#12345678; xD800 \0 A");
+}
+
+#[test]
+fn selector_errors() {
+    // Valid selectors
+    for css in &[
+        "*", "div", "div#a.b.c", "#♥", ".\\31 23", "[foo]", "[foo=\"bar\"]", "[foo~='bar' i]", "[foo^=bar]", "[foo$=\"]\"]",
+        "[foo*=bar]", "ul > li + li ~ li p", "div , p", "a:not(.b, [c])", "li:nth-child(2n + 1)", "li:NTH-LAST-CHILD(ODD)",
+        "li:nth-of-type(-n+3)", ":first-child", ":last-of-type", ":only-child", "input:checked", ":root", "p:empty", "* > p",
+        "*|rect",
+    ] {
+        assert!(Selector::parse(css).is_ok(), "{}", css);
+    }
+
+    // Invalid selectors
+    let errors = vec![
+        ("", SelectorErrorKind::EmptySelector, 0),
+        ("div,", SelectorErrorKind::EmptySelector, 4),
+        ("div,,p", SelectorErrorKind::EmptySelector, 4),
+        ("div >", SelectorErrorKind::EmptySelector, 5),
+        ("div > > p", SelectorErrorKind::EmptySelector, 6),
+        ("div[foo=", SelectorErrorKind::InvalidAttribute, 3),
+        ("div[foo%=bar]", SelectorErrorKind::InvalidAttribute, 3),
+        ("[foo=", SelectorErrorKind::InvalidAttribute, 0),
+        ("[foo", SelectorErrorKind::InvalidAttribute, 0),
+        ("[=x]", SelectorErrorKind::InvalidAttribute, 0),
+        ("p, [", SelectorErrorKind::InvalidAttribute, 3),
+        ("div > [foo", SelectorErrorKind::InvalidAttribute, 6),
+        ("p:hover", SelectorErrorKind::UnknownPseudoClass, 1),
+        ("p:first-line", SelectorErrorKind::UnknownPseudoClass, 1),
+        ("li:nth-child(whatever)", SelectorErrorKind::InvalidArgument, 2),
+        ("li:nth-child()", SelectorErrorKind::InvalidArgument, 2),
+        ("li:nth-child", SelectorErrorKind::InvalidArgument, 2),
+        ("li:first-child(2)", SelectorErrorKind::InvalidArgument, 2),
+        ("li:not", SelectorErrorKind::InvalidArgument, 2),
+        ("li:not(div[x=)", SelectorErrorKind::InvalidAttribute, 10),
+        ("div.", SelectorErrorKind::UnexpectedToken, 3),
+        ("a\"b", SelectorErrorKind::UnexpectedToken, 0),
+    ];
+    for (css, kind, position) in errors {
+        let err = Selector::parse(css).unwrap_err();
+        assert_eq!((err.kind, err.position), (kind, position), "{}", css);
+    }
+    assert_eq!(Selector::parse("div[foo=").unwrap_err().to_string(), "invalid attribute selector at position 3");
+    assert!("div > p".parse::<Selector>().is_ok());
+
+    // No panics
+    let dom = DOM::new("<p class=\"\u{FFFD}\">A</p><p>B</p>");
+    assert_eq!(dom.find(".\\D800").len(), 1);
+    assert_eq!(dom.find(".\\110000").len(), 1);
+    assert_eq!(dom.find(".\\0").len(), 1);
+    assert_eq!(dom.find("p:nth-child(2147483647n+1)").len(), 1);
+    assert_eq!(dom.find("p:nth-child(-2147483647n-2147483648)").len(), 0);
+    assert!(Selector::parse("p:nth-child(2147483647n+1)").is_ok());
+    assert!(Selector::parse("p:nth-child(2147483648)").is_err());
+
+    // Invalid parts of lenient selectors never match
+    assert_eq!(dom.find("p[class=").len(), 2);
+    assert_eq!(dom.find("p, [").len(), 2);
+    let dom = DOM::new("<div foo=\"bar\"><p>x</p></div>");
+    assert_eq!(dom.find("div[foo=").len(), 1);
+    assert!(dom.at("div").unwrap().matches("div[foo="));
+    for css in &[":not(", ":is(", ":where(", ":has(", "div:not(p"] {
+        assert!(dom.find(css).is_empty(), "{}", css);
+        assert!(dom.at(css).is_none(), "{}", css);
+        assert!(!dom.at("div").unwrap().matches(css), "{}", css);
+    }
+
+    // Fallible DOM methods
+    let dom = DOM::new("<div><p class=\"a\">A</p><p>B</p></div>");
+    assert_eq!(dom.try_find("p").unwrap().len(), 2);
+    assert_eq!(dom.try_find("div p:not(.a)").unwrap().iter().map(|x| x.text()).collect::<Vec<_>>(), ["B"]);
+    assert_eq!(dom.try_find("p[class=").unwrap_err().kind, SelectorErrorKind::InvalidAttribute);
+    assert_eq!(dom.try_at("p:last-child").unwrap().unwrap().text(), "B");
+    assert!(dom.try_at("span").unwrap().is_none());
+    assert!(dom.try_at("p:hover").is_err());
+    assert!(dom.at("p").unwrap().try_matches(".a").unwrap());
+    assert!(!dom.at("p").unwrap().try_matches("div > p:nth-child(2)").unwrap());
+    assert!(dom.at("p").unwrap().try_matches("div >").is_err());
+    assert_eq!(dom.find("* > p").len(), 2);
+    assert_eq!(dom.find("span , p").len(), 2);
+}
+
+#[test]
+fn precompiled_selectors() {
+    lazy_static! {
+        static ref LINKS: Selector = Selector::parse("a[href]:not([href^=\"#\"])").unwrap();
+        static ref ITEMS: Selector = Selector::parse("li.item").unwrap();
+    }
+
+    fn _assert_send_sync<T: Send + Sync>(_: &T) {}
+    _assert_send_sync(&*LINKS);
+
+    let pages = [
+        r##"<ul><li class="item"><a href="/a">A</a></li><li><a href="#top">Top</a></li></ul>"##,
+        r##"<ul><li class="item">B</li><li class="item"><a href="/c">C</a></li></ul><a href="/d">D</a>"##,
+    ];
+    let links: Vec<Vec<_>> = pages.iter().map(|x| DOM::new(x).find_with(&LINKS).iter().map(|x| x.text()).collect()).collect();
+    assert_eq!(links, [vec!["A"], vec!["C", "D"]]);
+
+    let dom = DOM::new(pages[1]);
+    let item = dom.at_with(&ITEMS).unwrap();
+    assert_eq!(item.text(), "B");
+    assert!(item.matches_with(&ITEMS));
+    assert!(!item.matches_with(&LINKS));
+    assert_eq!(item.following_with(Some(&ITEMS)).len(), 1);
+    assert_eq!(item.following_with(None).len(), 1);
+    assert_eq!(dom.at("li:last-child").unwrap().preceding_with(Some(&ITEMS)).iter().map(|x| x.text()).collect::<Vec<_>>(), ["B"]);
+    assert_eq!(dom.at("ul").unwrap().childs_with(Some(&ITEMS)).len(), 2);
+    assert_eq!(dom.at("a").unwrap().ancestors_with(Some(&ITEMS)).len(), 1);
+    assert!(dom.at("a").unwrap().ancestors_with(None).iter().all(|x| !x.matches_with(&LINKS)));
+    assert!(DOM::new("").at_with(&ITEMS).is_none());
+
+    // Shared between threads
+    let handle = std::thread::spawn(|| DOM::new(r#"<a href="/x">X</a>"#).find_with(&LINKS).len());
+    assert_eq!(handle.join().unwrap(), 1);
+}
+
+#[test]
+fn thread_safety() {
+    use std::sync::Arc;
+    use std::thread;
+
+    fn _assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    // Parse in worker threads and hand results back
+    let handles: Vec<_> = (0..4).map(|i| thread::spawn(move || {
+        DOM::new(&format!("<ul><li>{}</li><li>{}</li></ul>", i, i * 2))
+    })).collect();
+    let doms: Vec<DOM> = handles.into_iter().map(|x| x.join().unwrap()).collect();
+    _assert_send_sync(&doms[0]);
+    assert_eq!(doms.iter().map(|x| x.at("li:last-child").unwrap().text()).collect::<Vec<_>>(), ["0", "2", "4", "6"]);
+
+    // Share one document between readers
+    let dom = Arc::new(DOM::new("<div><p class=\"a\">A</p><p>B</p></div>"));
+    let readers: Vec<_> = (0..4).map(|_| {
+        let dom = dom.clone();
+        thread::spawn(move || (dom.find("p").len(), dom.at(".a").unwrap().next().unwrap().text(), dom.to_string()))
+    }).collect();
+    for reader in readers {
+        assert_eq!(reader.join().unwrap(), (2, "B".to_owned(), "<div><p class=\"a\">A</p><p>B</p></div>".to_owned()));
+    }
+
+    // Element handles outlive the thread that created them
+    let p = thread::spawn(|| DOM::new("<div><p>C</p></div>").at("p").unwrap()).join().unwrap();
+    assert_eq!(p.parent().unwrap().tag(), Some("div"));
+}
+
+#[test]
+fn arena_storage() {
+    // Fragments from the same document
+    let dom = DOM::new("<ul><li>A</li><li>B</li></ul>");
+    let ul = dom.at("ul").unwrap();
+    ul.append_content(&ul);
+    assert_eq!(dom.to_string(), "<ul><li>A</li><li>B</li><ul><li>A</li><li>B</li></ul></ul>");
+    ul.at("ul").unwrap().replace(&dom.at("li").unwrap());
+    assert_eq!(dom.to_string(), "<ul><li>A</li><li>B</li><li>A</li></ul>");
+    assert_eq!(dom.find("li:last-child").len(), 1);
+    assert_eq!(dom.find("li:nth-child(2) ~ li").iter().map(|x| x.text()).collect::<Vec<_>>(), ["A"]);
+
+    // Detached elements stay usable
+    let b = dom.at("li:nth-child(2)").unwrap();
+    assert_eq!(b.remove().childs(None).len(), 2);
+    assert!(b.parent().is_none());
+    assert!(b.next().is_none() && b.prev().is_none());
+    assert!(b.matches("li:first-child:last-child"));
+    assert_eq!(b.append_content("<i>C</i>").to_string(), "<li>B<i>C</i></li>");
+    assert_eq!(dom.to_string(), "<ul><li>A</li><li>A</li></ul>");
+
+    // Moved elements keep their identity
+    let dom = DOM::new("<div><p>A</p></div><section></section>");
+    let p = dom.at("p").unwrap();
+    p.wrap("<b></b>");
+    assert_eq!(p.parent().unwrap().tag(), Some("b"));
+    assert_eq!(p.ancestors(None).iter().map(|x| x.tag().unwrap().to_owned()).collect::<Vec<_>>(), ["b", "div"]);
+    assert_eq!(dom.at("section").unwrap().preceding(None).len(), 1);
+}
+
+#[test]
+fn lazy_iterators() {
+    let dom = DOM::new("<div id=\"a\"><p id=\"b\"><b id=\"c\">C</b></p><p id=\"d\">D</p><i id=\"e\">E</i></div><p id=\"f\">F</p>");
+    let _ids = |x: victoria_dom::Elements| x.map(|x| x.attr("id").unwrap()).collect::<Vec<_>>();
+
+    assert_eq!(_ids(dom.find_iter("p")), ["b", "d", "f"]);
+    assert_eq!(_ids(dom.at("div").unwrap().find_iter("p")), ["b", "d"]);
+    assert_eq!(_ids(dom.find_iter("[id]")), dom.find("[id]").iter().map(|x| x.attr("id").unwrap()).collect::<Vec<_>>());
+
+    // Same scope as "find"
+    let nested = DOM::new("<section><div><p>A</p><div><p>B</p></div></div></section>");
+    let div = nested.at("div").unwrap();
+    for css in &["div p", "section > div > p", "div > p", "p", ":not(div div) > p"] {
+        let texts = div.find(css).iter().map(|x| x.text()).collect::<Vec<_>>();
+        assert_eq!(div.find_iter(css).map(|x| x.text()).collect::<Vec<_>>(), texts);
+    }
+    assert_eq!(div.find_iter("div p").map(|x| x.text()).collect::<Vec<_>>(), ["B"]);
+    assert_eq!(_ids(dom.at("div").unwrap().descendants()), ["b", "c", "d", "e"]);
+    assert_eq!(_ids(dom.at("div").unwrap().children_iter(None)), ["b", "d", "e"]);
+    assert_eq!(_ids(dom.at("div").unwrap().children_iter(Some("p"))), ["b", "d"]);
+    assert_eq!(_ids(dom.at("b").unwrap().ancestors_iter(None)), ["b", "a"]);
+    assert_eq!(_ids(dom.at("#d").unwrap().siblings_iter(None)), ["b", "e"]);
+    assert_eq!(_ids(dom.at("#d").unwrap().siblings_iter(Some(":not(p)"))), ["e"]);
+    assert_eq!(dom.at("#c").unwrap().descendants().count(), 0);
+    assert_eq!(DOM::new("<p>A</p>").at("p").unwrap().remove().siblings_iter(None).count(), 0);
+
+    // Composes with iterator adapters
+    assert_eq!(dom.descendants().position(|x| x.tag() == Some("i")), Some(4));
+    assert_eq!(dom.find_iter("p").skip_while(|x| x.text() != "D").map(|x| x.text()).collect::<Vec<_>>(), ["D", "F"]);
+
+    // Mutations between steps are allowed
+    for p in dom.find_iter("p") {
+        p.set_attr("class", "x");
+    }
+    assert_eq!(dom.find(".x").len(), 3);
+    let mut iter = dom.at("div").unwrap().children_iter(None);
+    iter.next().unwrap().remove();
+    assert_eq!(_ids(iter), ["d", "e"]);
+
+    // Removing the returned elements
+    let list = DOM::new("<ul><li>1</li><li>2<b>x</b></li><li>3</li></ul><p>4</p>");
+    for li in list.find_iter("li") { li.remove(); }
+    assert_eq!(list.to_string(), "<ul></ul><p>4</p>");
+    for b in list.descendants() { b.remove(); }
+    assert_eq!(list.to_string(), "");
+    let list = DOM::new("<ul><li>1</li><li>2</li><li>3</li></ul>");
+    let ul = list.at("ul").unwrap();
+    for li in ul.children_iter(None) {
+        li.next().map(|x| x.remove());
+        li.append_content("<b>x</b>");
+    }
+    assert_eq!(list.to_string(), "<ul><li>1<b>x</b></li><li>3<b>x</b></li></ul>");
+}
+
+#[test]
+fn non_element_nodes() {
+    let html = "<!DOCTYPE html><?xml-stylesheet href=\"a.xsl\"?><div>A<!-- B --><![CDATA[C]]><script>D</script></div>";
+    let dom = DOM::new(html);
+
+    let nodes = dom.descendant_nodes();
+    assert_eq!(nodes.iter().map(|x| x.node_type()).collect::<Vec<_>>(), [
+        NodeType::Doctype, NodeType::ProcessingInstruction, NodeType::Tag, NodeType::Text,
+        NodeType::Comment, NodeType::CData, NodeType::Tag, NodeType::Text,
+    ]);
+    assert_eq!(nodes.iter().map(|x| x.content()).collect::<Vec<_>>(), [
+        " html", "xml-stylesheet href=\"a.xsl\"", "A<!-- B --><![CDATA[C]]><script>D</script>", "A", " B ", "C", "D", "D",
+    ]);
+    assert_eq!(nodes[0].doctype().as_deref(), Some(" html"));
+    assert_eq!(nodes[1].processing_instruction().as_deref(), Some("xml-stylesheet href=\"a.xsl\""));
+    assert_eq!(nodes[4].comment().as_deref(), Some(" B "));
+    assert_eq!(nodes[5].cdata().as_deref(), Some("C"));
+    assert_eq!(nodes[3].comment(), None);
+    assert_eq!(nodes[2].doctype(), None);
+    assert_eq!(nodes[3].tag(), None);
+    assert_eq!(dom.node_type(), NodeType::Root);
+
+    // Child nodes
+    let div = dom.at("div").unwrap();
+    assert_eq!(div.child_nodes().iter().map(|x| x.to_string()).collect::<Vec<_>>(), ["A", "<!-- B -->", "<![CDATA[C]]>", "<script>D</script>"]);
+    assert_eq!(dom.child_nodes().len(), 3);
+    assert!(nodes[3].child_nodes().is_empty());
+    assert_eq!(nodes[3].parent().unwrap().tag(), Some("div"));
+
+    // Non-element nodes can be removed
+    div.child_nodes()[1].remove();
+    assert_eq!(div.to_string(), "<div>A<![CDATA[C]]><script>D</script></div>");
+}
+
+#[test]
+fn text_kinds() {
+    let dom = DOM::new("<!DOCTYPE html><?pi?><title>A &amp; B</title><p>C<![CDATA[D]]><!--E--></p>");
+    let kinds: Vec<_> = dom.descendant_nodes().iter().filter_map(|x| x.text_kind()).collect();
+    assert_eq!(kinds, [TextKind::Doctype, TextKind::ProcessingInstruction, TextKind::Raw, TextKind::Text, TextKind::CData, TextKind::Comment]);
+    assert_eq!(dom.text_kind(), None);
+    assert_eq!(dom.at("p").unwrap().text_kind(), None);
+
+    let nodes = dom.descendant_nodes();
+    let raw = nodes.iter().find(|x| x.text_kind() == Some(TextKind::Raw)).unwrap();
+    assert_eq!((raw.node_type(), raw.content()), (NodeType::Text, "A & B".to_owned()));
+    assert_eq!(dom.at("p").unwrap().rtext(), "CD");
+    assert!(DOM::new("<p><!--E--><?pi?></p>").at("p:empty").is_some());
+}
+
+#[test]
+fn xml_mode() {
+    let xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0">
+  <channel>
+    <title>Feed &amp; more</title>
+    <item><title>A</title><link>http://a</link><Image src="a.png"/></item>
+    <item><title>B</title><link>http://b</link><p><div/></p><br>C</br></item>
+  </channel>
+</rss>"#;
+
+    // Auto-detected from the XML declaration
+    let dom = DOM::new(xml);
+    assert!(dom.is_xml());
+    assert!(!DOM::new("<rss></rss>").is_xml());
+    assert!(!DOM::new("<?xml-stylesheet href=\"a.xsl\"?><rss></rss>").is_xml());
+    assert!(DOM::new_xml("<rss></rss>").is_xml());
+    assert_eq!(DOM::new_xml(xml).to_string(), dom.to_string());
+
+    // No raw text elements and no "image" alias
+    assert_eq!(dom.at("channel > title").unwrap().text(), "Feed & more");
+    assert_eq!(dom.find("item > title").iter().map(|x| x.text()).collect::<Vec<_>>(), ["A", "B"]);
+    assert_eq!(dom.find("link").iter().map(|x| x.text()).collect::<Vec<_>>(), ["http://a", "http://b"]);
+    assert!(dom.at("img").is_none());
+    assert_eq!(dom.at("Image").unwrap().attr("src").as_deref(), Some("a.png"));
+
+    // Case is preserved
+    assert_eq!(dom.at("Image").unwrap().tag(), Some("Image"));
+    assert!(dom.at("image").is_none());
+    let dom2 = DOM::new_xml("<Foo Bar=\"1\"><baz/></Foo>");
+    assert_eq!(dom2.at("Foo").unwrap().attrs().keys().collect::<Vec<_>>(), ["Bar"]);
+    assert!(dom2.at("[Bar]").is_some());
+    assert!(dom2.at("[bar]").is_none());
+
+    // No implicit closing and self-closing for all elements
+    assert_eq!(dom.at("p").unwrap().childs(None).iter().map(|x| x.tag().unwrap().to_owned()).collect::<Vec<_>>(), ["div"]);
+    assert_eq!(dom.at("div").unwrap().content(), "");
+    assert_eq!(dom.at("br").unwrap().text(), "C");
+    assert_eq!(DOM::new_xml("<ul><li>A<li>B</ul>").find("li > li").len(), 1);
+    assert_eq!(DOM::new_xml("<script><b>A</b></script>").find("script > b").len(), 1);
+
+    // Fragments are parsed in the same mode
+    dom.at("channel").unwrap().append_content("<Item><Title>C</Title></Item>");
+    assert_eq!(dom.at("Item > Title").unwrap().text(), "C");
+
+    // Strict mode has no optional end tags in XML
+    assert!(DOM::parse_strict("<?xml version=\"1.0\"?><a><li></a>").is_err());
+    assert!(DOM::parse_strict("<?xml version=\"1.0\"?><a><li/></a>").is_ok());
+}
+
+#[test]
+fn xml_rendering() {
+    // HTML documents
+    let dom = DOM::new("<div><img src=\"a.png\"><p></p><br><option selected>A</option></div>");
+    assert_eq!(dom.to_string(), "<div><img src=\"a.png\"><p></p><br><option selected>A</option></div>");
+    assert_eq!(dom.to_xml(), "<div><img src=\"a.png\"/><p/><br/><option selected=\"selected\">A</option></div>");
+    assert_eq!(dom.at("p").unwrap().to_xml(), "<p/>");
+    assert_eq!(dom.at("option").unwrap().to_string(), "<option selected>A</option>");
+    let dom = DOM::new("<script>if (a < b && c) {}</script><style>p{}</style><script>x = ']]>' < 1</script>");
+    assert_eq!(dom.to_xml(), "<script><![CDATA[if (a < b && c) {}]]></script><style>p{}</style><script><![CDATA[x = ']]]]><![CDATA[>' < 1]]></script>");
+    assert_eq!(dom.to_string(), "<script>if (a < b && c) {}</script><style>p{}</style><script>x = ']]>' < 1</script>");
+
+    // XML documents
+    let xml = "<?xml version=\"1.0\"?><urlset><url><loc>http://a</loc><priority></priority><br/></url></urlset>";
+    let dom = DOM::new(xml);
+    assert_eq!(dom.to_string(), "<?xml version=\"1.0\"?><urlset><url><loc>http://a</loc><priority/><br/></url></urlset>");
+    assert_eq!(dom.to_xml(), dom.to_string());
+    assert_eq!(dom.at("url").unwrap().content(), "<loc>http://a</loc><priority/><br/>");
+    assert_eq!(DOM::new_xml("<a b></a>").to_string(), "<a b=\"b\"/>");
+    assert_eq!(DOM::new_xml("<x>&lt;&amp;</x>").to_string(), "<x>&lt;&amp;</x>");
+}
+
+#[test]
+fn namespaces() {
+    const XHTML: &str = "http://www.w3.org/1999/xhtml";
+    const SVG: &str = "http://www.w3.org/2000/svg";
+    const XLINK: &str = "http://www.w3.org/1999/xlink";
+
+    let xml = r##"<?xml version="1.0"?>
+<doc xmlns="urn:doc" xmlns:svg="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink">
+  <svg:svg><svg:rect id="a"/><svg:a xlink:href="#a">A</svg:a></svg:svg>
+  <rect id="b"/>
+  <other xmlns=""><rect id="c"/></other>
+</doc>"##;
+    let dom = DOM::new(xml);
+    let _ids = |x: Vec<DOM>| x.iter().map(|x| x.attr("id").unwrap_or_default()).collect::<Vec<_>>();
+    let _select = |css: &str| Selector::parse_with_namespaces(css, &[("s", SVG), ("x", XLINK), ("d", "urn:doc")]).unwrap();
+
+    // Namespace of elements
+    assert_eq!(dom.at("#a").unwrap().namespace().as_deref(), Some(SVG));
+    assert_eq!(dom.at("#b").unwrap().namespace().as_deref(), Some("urn:doc"));
+    assert_eq!(dom.at("#c").unwrap().namespace(), None);
+    assert_eq!(DOM::new("<p><svg><rect/></svg><math><mi>x</mi></math></p>").find("*").iter().map(|x| x.namespace()).collect::<Vec<_>>(),
+        [Some(XHTML.to_owned()), Some(SVG.to_owned()), Some(SVG.to_owned()), Some("http://www.w3.org/1998/Math/MathML".to_owned()), Some("http://www.w3.org/1998/Math/MathML".to_owned())]);
+
+    // Type selectors
+    assert_eq!(_ids(dom.find("rect")), ["a", "b", "c"]);
+    assert_eq!(_ids(dom.find_with(&_select("s|rect"))), ["a"]);
+    assert_eq!(_ids(dom.find_with(&_select("d|rect"))), ["b"]);
+    assert_eq!(_ids(dom.find_with(&_select("|rect"))), ["c"]);
+    assert_eq!(_ids(dom.find_with(&_select("*|rect"))), ["a", "b", "c"]);
+    assert_eq!(dom.find_with(&_select("s|*")).len(), 3);
+    assert_eq!(_ids(dom.find_with(&_select("s|svg > s|rect, |other > |rect"))), ["a", "c"]);
+    assert_eq!(dom.find_with(&_select("s|rect:not(s|*)")).len(), 0);
+
+    // Default namespace
+    let selector = Selector::parse_with_namespaces("rect", &[("", "urn:doc")]).unwrap();
+    assert_eq!(_ids(dom.find_with(&selector)), ["b"]);
+
+    // Attribute selectors
+    assert_eq!(dom.find_with(&_select("[x|href]")).len(), 1);
+    assert_eq!(dom.find_with(&_select("[x|href=\"#a\"]")).len(), 1);
+    assert_eq!(dom.find_with(&_select("[*|href]")).len(), 1);
+    assert_eq!(dom.find_with(&_select("[|href]")).len(), 0);
+    assert_eq!(dom.find_with(&_select("[s|href]")).len(), 0);
+    assert_eq!(dom.find_with(&_select("[|id]")).len(), 3);
+
+    // Unknown prefixes
+    assert_eq!(Selector::parse("svg|rect").unwrap_err().kind, SelectorErrorKind::UnknownNamespace);
+    assert_eq!(Selector::parse("[svg|href]").unwrap_err().kind, SelectorErrorKind::UnknownNamespace);
+    assert!(Selector::parse("*|rect, |rect, [*|id], [|id]").is_ok());
+    assert!(dom.at("svg|rect").is_none());
+    assert_eq!(Selector::parse("a|b|c").unwrap_err().kind, SelectorErrorKind::UnexpectedToken);
+
+    // HTML inside SVG integration points
+    let dom = DOM::new("<div id=x><svg><foreignObject><div id=y><svg><desc id=d><b id=z>A</b></desc></svg></div></foreignObject></svg></div>");
+    let html = Selector::parse_with_namespaces("h|div, h|b", &[("h", XHTML)]).unwrap();
+    let svg = Selector::parse_with_namespaces("s|div, s|b, s|desc", &[("s", SVG)]).unwrap();
+    assert_eq!(_ids(dom.find_with(&html)), ["x", "y", "z"]);
+    assert_eq!(_ids(dom.find_with(&svg)), ["d"]);
+    assert_eq!(dom.at("foreignobject").unwrap().namespace().as_deref(), Some(SVG));
+}
+
+#[test]
+fn pretty_printing() {
+    let html = "<!DOCTYPE html>\n<html><head><title> A  B </title><script>if (a) {\n  b();\n}</script></head>\
+        <body>\n  <!-- nav -->\n  <div id=\"main\">Intro <a href=\"/\">home</a>\n<p>First\u{a0}</p><p></p><br>\
+        <pre>\n  x\n    y</pre><textarea>  1  2 </textarea>Outro</div></body></html>";
+    let dom = DOM::new(html);
+    assert_eq!(dom.to_pretty_string("  "), "<!DOCTYPE html>
+<html>
+  <head>
+    <title> A  B </title>
+    <script>if (a) {
+  b();
+}</script>
+  </head>
+  <body>
+    <!-- nav -->
+    <div id=\"main\">
+      Intro <a href=\"/\">home</a>
+      <p>First\u{a0}</p>
+      <p></p>
+      <br>
+      <pre>
+  x
+    y</pre>
+      <textarea>  1  2 </textarea>Outro
+    </div>
+  </body>
+</html>
+");
+
+    // Pretty printing is stable
+    let pretty = dom.to_pretty_string("\t");
+    assert_eq!(DOM::new(&pretty).to_pretty_string("\t"), pretty);
+    assert_eq!(dom.at("pre").unwrap().to_pretty_string("\t"), "<pre>\n  x\n    y</pre>\n");
+    assert_eq!(dom.at("a").unwrap().to_pretty_string("\t"), "<a href=\"/\">home</a>\n");
+    assert_eq!(dom.at("head").unwrap().to_pretty_string("\t"), "<head>\n\t<title> A  B </title>\n\t<script>if (a) {\n  b();\n}</script>\n</head>\n");
+    assert_eq!(DOM::new("").to_pretty_string("  "), "");
+
+    // XML documents
+    let dom = DOM::new_xml("<feed>\n<entry><title>A <b>B</b></title><empty/></entry></feed>");
+    assert_eq!(dom.to_pretty_string("  "), "<feed>\n  <entry>\n    <title>A <b>B</b></title>\n    <empty/>\n  </entry>\n</feed>\n");
+}
+
+#[test]
+fn render_options() {
+    let dom = DOM::new(r#"<form z="1" a="2"><input type="checkbox" checked><p title='"x"'>1 < 2 & 'y'</p></form>"#);
+    let default = RenderOptions::default();
+    assert_eq!(dom.render(&default), dom.to_string());
+    assert_eq!(dom.render(&default), r#"<form a="2" z="1"><input checked type="checkbox"><p title="&quot;x&quot;">1 &lt; 2 &amp; &#39;y&#39;</p></form>"#);
+
+    // Attribute order, boolean attributes and quotes
+    let options = RenderOptions { attr_order: AttrOrder::Source, expand_boolean_attrs: true, quote: Quote::Single, ..RenderOptions::default() };
+    assert_eq!(dom.render(&options), r#"<form z='1' a='2'><input type='checkbox' checked='checked'><p title='&quot;x&quot;'>1 &lt; 2 &amp; &#39;y&#39;</p></form>"#);
+
+    // Escaping and entities
+    let options = RenderOptions { escape: Escape::Minimal, quote: Quote::Auto, ..RenderOptions::default() };
+    assert_eq!(dom.at("p").unwrap().render(&options), r#"<p title='"x"'>1 &lt; 2 &amp; 'y'</p>"#);
+    let options = RenderOptions { escape: Escape::Minimal, entities: Entities::Numeric, ..RenderOptions::default() };
+    assert_eq!(dom.at("p").unwrap().render(&options), r#"<p title="&#34;x&#34;">1 &#60; 2 &#38; 'y'</p>"#);
+
+    // Optional end tags
+    let dom = DOM::new("<ul><li>A</li><li>B</li></ul><p>C</p><p>D <b>E</b></p><table><tr><td>1</td></tr></table><div><p>F</p> </div><span><p>G</p></span>");
+    let options = RenderOptions { omit_optional_end_tags: true, ..RenderOptions::default() };
+    let html = dom.render(&options);
+    assert_eq!(html, "<ul><li>A<li>B</ul><p>C<p>D <b>E</b><table><tr><td>1</table><div><p>F</p> </div><span><p>G</p></span>");
+    assert_eq!(DOM::new(&html).to_string(), dom.to_string());
+
+    // XML
+    let options = RenderOptions { xml: Some(true), omit_optional_end_tags: true, ..RenderOptions::default() };
+    assert_eq!(DOM::new("<p><input checked></p>").render(&options), r#"<p><input checked="checked"/></p>"#);
+    let options = RenderOptions { indent: Some("  ".to_owned()), attr_order: AttrOrder::Source, ..RenderOptions::default() };
+    assert_eq!(DOM::new(r#"<div b="1" a="2"><p>A</p></div>"#).render(&options), "<div b=\"1\" a=\"2\">\n  <p>A</p>\n</div>\n");
+}
+
+#[test]
+fn attribute_order() {
+    let dom = DOM::new(r#"<div id="a" data-z="1" class="x" data-a="2" class="y" hidden id="b">A</div>"#);
+    let div = dom.at("div").unwrap();
+    let attrs = div.attrs();
+    assert_eq!(attrs.keys().collect::<Vec<_>>(), ["id", "data-z", "class", "data-a", "hidden"]);
+    assert_eq!(attrs.len(), 5);
+    assert_eq!(div.attr("id").unwrap(), "b");
+    assert_eq!(div.attr("class").unwrap(), "y");
+    assert_eq!(attrs.duplicates(), [("class".to_owned(), Some("x".to_owned())), ("id".to_owned(), Some("a".to_owned()))]);
+    assert!(dom.at("[id=b].y").is_some());
+
+    // Modified attributes keep their position, new ones are added to the end
+    div.set_attr("data-z", "3").set_attr("title", "T").remove_attr("data-a");
+    let options = RenderOptions { attr_order: AttrOrder::Source, ..RenderOptions::default() };
+    assert_eq!(div.render(&options), r#"<div id="b" data-z="3" class="y" hidden title="T">A</div>"#);
+    assert_eq!(div.to_string(), r#"<div class="y" data-z="3" hidden id="b" title="T">A</div>"#);
+
+    // Ordered list
+    let attrs: Attrs = vec![("b".to_owned(), None), ("a".to_owned(), Some("1".to_owned()))].into_iter().collect();
+    assert_eq!(attrs.into_iter().collect::<Vec<_>>(), [("b".to_owned(), None), ("a".to_owned(), Some("1".to_owned()))]);
+    assert!(DOM::new("<p>").at("p").unwrap().attrs().duplicates().is_empty());
+}
+
+#[test]
+fn source_positions() {
+    let html = "<!DOCTYPE html>\n<div class=\"x\">\n\tÄ <b>B</b> 1 < 2<br>\n<script>var a;</script><!-- c --></div>";
+    let dom = DOM::new(html);
+    let _source = |x: Span| &html[x.start.offset .. x.end.offset];
+
+    let div = dom.at("div").unwrap().span().unwrap();
+    assert_eq!(_source(div.open), "<div class=\"x\">");
+    assert_eq!(_source(div.close.unwrap()), "</div>");
+    assert_eq!((div.close.unwrap().start.line, div.close.unwrap().start.column), (4, 34));
+    assert_eq!(dom.at("div").unwrap().child_nodes()[0].span().unwrap().open.end.line, 3);
+
+    let b = dom.at("b").unwrap().span().unwrap();
+    assert_eq!((b.open.start.line, b.open.start.column, b.open.end.column), (3, 4, 7));
+    assert_eq!(_source(b.close.unwrap()), "</b>");
+    let br = dom.at("br").unwrap().span().unwrap();
+    assert_eq!((_source(br.open), br.close), ("<br>", None));
+    let script = dom.at("script").unwrap();
+    assert_eq!(_source(script.span().unwrap().close.unwrap()), "</script>");
+    assert_eq!(_source(script.child_nodes()[0].span().unwrap().open), "var a;");
+
+    // Text and other nodes
+    let spans = dom.descendant_nodes().iter().filter(|x| x.tag().is_none()).map(|x| _source(x.span().unwrap().open)).collect::<Vec<_>>();
+    assert_eq!(spans, ["<!DOCTYPE html>", "\n", "\n\tÄ ", "B", " 1 <", " 2", "\n", "var a;", "<!-- c -->"]);
+
+    // Nodes without source
+    assert!(dom.span().is_none());
+    dom.at("b").unwrap().append("<i>I</i>");
+    assert!(dom.at("i").unwrap().span().is_none());
+    assert_eq!(dom.at("b").unwrap().span(), Some(b));
+}
+
+#[test]
+fn lossless_rendering() {
+    for html in &[
+        "",
+        "<!doctype html>\n<HTML><Body CLASS = 'a'  hidden>Fish &amp chips&nbsp;&#33;</BODY></html >",
+        "<p>a<div>b</div></p></br>x < y <<",
+        "</stray><ul><li>A<li>B</ul><table><tr><td>1<td>2</table>",
+        "<div><p>A</b>B</span> <img src=x /><image></div>trailing <!-- c -- > <?pi?>",
+        "<script>if (a < b) {}</ script ><textarea>&lt;&gt;</textarea><foo/>",
+        "<br/><p/>text", "<p/>x", "<div/>x", "<a name=top/>x",
+    ] {
+        let dom = DOM::new_lossless(html);
+        assert_eq!(&dom.to_string(), html);
+        assert_eq!(dom.content(), dom.to_string());
+    }
+    let xml = "<?xml version=\"1.0\"?>\n<Feed><Entry a='1'/><![CDATA[<x>]]></Feed>";
+    assert_eq!(DOM::new_lossless(xml).to_string(), xml);
+    let dom = DOM::new_lossless(xml);
+    dom.at("Entry").unwrap().append_content("<b/>");
+    assert_eq!(dom.to_string(), "<?xml version=\"1.0\"?>\n<Feed><Entry a=\"1\"><b/></Entry><![CDATA[<x>]]></Feed>");
+
+    // Modified nodes are rendered again
+    let dom = DOM::new_lossless("<div Id=main>\n  <p class=a>A &amp; B<p>C\n  <input type=text DISABLED></div>");
+    dom.at("input").unwrap().set_attr("value", "v");
+    assert_eq!(dom.to_string(), "<div Id=main>\n  <p class=a>A &amp; B<p>C\n  <input type=text DISABLED value=\"v\"></div>");
+    dom.at("p").unwrap().append_content("<b>!</b>");
+    assert_eq!(dom.to_string(), "<div Id=main>\n  <p class=a>A &amp; B<b>!</b><p>C\n  <input type=text DISABLED value=\"v\"></div>");
+
+    // Omitted end tags are added if required
+    dom.at("p").unwrap().append("<span>D</span>");
+    assert_eq!(dom.at("div").unwrap().to_string(), "<div Id=main>\n  <p class=a>A &amp; B<b>!</b></p><span>D</span><p>C\n  <input type=text DISABLED value=\"v\"></div>");
+    assert_eq!(DOM::new(&dom.to_string()).to_string(), DOM::new(&dom.to_string()).to_string());
+
+    // Unchanged attributes and ignored tokens are kept
+    let dom = DOM::new_lossless("<DIV Id=main  data-x = '1' class=\"a b\" hidden>A</DIV>");
+    dom.at("div").unwrap().set_attr("class", "c").set_attr("title", "t");
+    assert_eq!(dom.to_string(), "<DIV Id=main data-x = '1' class=\"c\" hidden title=\"t\">A</DIV>");
+    let dom = DOM::new_lossless("<p id=a></b>x</p><Img SRC=a.png />");
+    dom.at("p").unwrap().remove_attr("missing").set_attr("id", "a");
+    assert_eq!(dom.to_string(), "<p id=a></b>x</p><Img SRC=a.png />");
+    dom.at("p").unwrap().set_attr("id", "b");
+    dom.at("img").unwrap().set_attr("alt", None);
+    assert_eq!(dom.to_string(), "<p id=\"b\"></b>x</p><Img SRC=a.png alt/>");
+
+    // Other modes
+    let dom = DOM::new_lossless("<P CLASS=x>A<br>");
+    assert_eq!(dom.to_xml(), "<p class=\"x\">A<br/></p>");
+    assert_eq!(dom.render(&RenderOptions { lossless: false, ..RenderOptions::default() }), "<p class=\"x\">A<br></p>");
+    assert_eq!(DOM::new("<P CLASS=x>A<br>").to_string(), "<p class=\"x\">A<br></p>");
+}
+
+#[test]
+fn tokenizer() {
+    let html = "<!DOCTYPE html>\n<P Class=\"a>b\" hidden>1 < 2 &amp; 3<br/></p><script>\nif (a < b) {}\n</SCRIPT ><?x y?><![CDATA[c]]>";
+    let tokens: Vec<_> = Tokenizer::new(html).collect();
+    assert_eq!(tokens.iter().map(|x| x.source).collect::<String>(), html);
+    assert_eq!(tokens.iter().map(|x| x.kind.clone()).collect::<Vec<_>>(), [
+        TokenKind::Doctype(" html"),
+        TokenKind::Text("\n"),
+        TokenKind::StartTag { name: "P", attrs: vec![("Class", Some("a>b")), ("hidden", None)], self_closing: false },
+        TokenKind::Text("1 <"),
+        TokenKind::Text(" 2 &amp; 3"),
+        TokenKind::StartTag { name: "br", attrs: vec![], self_closing: true },
+        TokenKind::EndTag("p"),
+        TokenKind::StartTag { name: "script", attrs: vec![], self_closing: false },
+        TokenKind::RawText("\nif (a < b) {}\n"),
+        TokenKind::EndTag("SCRIPT"),
+        TokenKind::ProcessingInstruction("x y"),
+        TokenKind::CData("c"),
+    ]);
+    assert_eq!((tokens[2].offset, tokens[2].source), (16, "<P Class=\"a>b\" hidden>"));
+
+    // No raw text in XML
+    let kinds: Vec<_> = Tokenizer::new_xml("<script>a<b/></script>").map(|x| x.kind).collect();
+    assert_eq!(kinds[2], TokenKind::StartTag { name: "b", attrs: vec![], self_closing: true });
+
+    // Multi-line raw text
+    let dom = DOM::new("<script>\nvar x = '<p>';\n</script>\n<textarea>a &amp;\n<b></textarea>");
+    assert_eq!(dom.at("script").unwrap().text(), "\nvar x = '<p>';\n");
+    assert_eq!(dom.at("textarea").unwrap().text(), "a &\n<b>");
+    assert!(dom.at("p").is_none());
+
+    // Stray "<"
+    assert_eq!(DOM::new("<<p>x").to_string(), "&lt;<p>x</p>");
+    assert_eq!(Tokenizer::new("").count(), 0);
+
+    // Unterminated markup is text, and is scanned only once
+    for opener in &["<!--", "<!DOCTYPE x", "<![CDATA[", "<?"] {
+        let html = opener.repeat(50_000);
+        assert_eq!(DOM::new(&html).text(), html, "{}", opener);
+        let mut parser = EventParser::new();
+        parser.feed(&html);
+        parser.finish();
+        assert_eq!(parser.map(|x| match x { Event::Text { content, .. } => content, _ => String::new() }).collect::<String>(), html);
+    }
+    assert_eq!(DOM::new("<!-- a -- b --><!--").to_string(), "<!-- a -- b -->&lt;!--");
+}
+
+#[test]
+fn streaming_events() {
+    let html = "<!DOCTYPE html><div id=\"a\"><p>A &amp; B<p>C<table><tr><td>D<td>E</table><!-- x --><script>a < b</script></div>";
+    let rebuild = |events: &[Event]| events.iter().map(|x| match *x {
+        Event::StartElement { ref name, ref attrs } => {
+            format!("<{}{}>", name, attrs.iter().map(|(k, v)| format!(" {}=\"{}\"", k, v.as_ref().map_or("", |x| x.as_str()))).collect::<String>())
+        },
+        Event::EndElement { ref name } => format!("</{}>", name),
+        Event::Text { kind: TextKind::Text, ref content } => content.replace("&", "&amp;"),
+        Event::Text { kind: TextKind::Comment, ref content } => format!("<!--{}-->", content),
+        Event::Text { kind: TextKind::Doctype, ref content } => format!("<!DOCTYPE{}>", content),
+        Event::Text { ref content, .. } => content.clone(),
+    }).collect::<String>();
+
+    let mut parser = EventParser::new();
+    parser.feed(html);
+    parser.finish();
+    let events: Vec<_> = parser.collect();
+    assert_eq!(rebuild(&events), DOM::new(html).to_string());
+
+    // Same events for every split
+    for i in 0 .. html.len() + 1 {
+        let mut parser = EventParser::new();
+        parser.feed(&html[.. i]);
+        parser.feed(&html[i ..]);
+        parser.finish();
+        assert_eq!(parser.collect::<Vec<_>>(), events);
+    }
+
+    // XML declaration, split before it is complete
+    let mut parser = EventParser::new();
+    parser.feed(" <?x");
+    parser.feed("ml version=\"1.0\"?><a><B/></a>");
+    parser.finish();
+    let names: Vec<_> = parser.filter_map(|x| match x {
+        Event::StartElement { name, .. } => Some(format!("<{}>", name)),
+        Event::EndElement { name } => Some(format!("</{}>", name)),
+        _ => None,
+    }).collect();
+    assert_eq!(names.concat(), "<a><B></B></a>");
+
+    // Reader
+    let events: Vec<_> = EventReader::new_xml("<a><B/>ü</a>".as_bytes()).map(|x| x.unwrap()).collect();
+    assert_eq!(events.len(), 5);
+    assert_eq!(events[1], Event::StartElement { name: "B".into(), attrs: Attrs::new() });
+    assert_eq!(events[3], Event::Text { kind: TextKind::Text, content: "ü".into() });
+
+    // Invalid UTF-8 is replaced like by Parser
+    let events: Vec<_> = EventReader::new(&b"<p>\xff</p>\xe2\x98"[..]).map(|x| x.unwrap()).collect();
+    assert_eq!(events[1], Event::Text { kind: TextKind::Text, content: "\u{FFFD}".into() });
+    assert_eq!(events[3], Event::Text { kind: TextKind::Text, content: "\u{FFFD}".into() });
+
+    // Large text in small chunks
+    let text = "Fish &amp; chips ".repeat(100_000);
+    let mut parser = EventParser::new();
+    for chunk in text.as_bytes().chunks(100) { parser.feed(std::str::from_utf8(chunk).unwrap()); }
+    parser.finish();
+    assert_eq!(parser.collect::<Vec<_>>(), [Event::Text { kind: TextKind::Text, content: "Fish & chips ".repeat(100_000) }]);
+}
+
+#[test]
+fn incremental_parsing() {
+    let html = "<!DOCTYPE html>\n<div class=\"ä b\"><p>Fish &amp; chips &#x2603;\n<p>Pie<script>if (a < b) { x = '</p>'; }</script><textarea>&lt;ö</textarea></div>";
+    let parse = |chunks: &[&[u8]]| {
+        let mut parser = Parser::new();
+        for chunk in chunks { parser.feed(chunk); }
+        parser.finish()
+    };
+    let dom = DOM::new(html);
+    let spans = |dom: &DOM| dom.find("*").iter().map(|x| x.span()).collect::<Vec<_>>();
+
+    // Every split (including inside UTF-8 sequences)
+    let bytes = html.as_bytes();
+    for i in 0 .. bytes.len() + 1 {
+        let result = parse(&[&bytes[.. i], &bytes[i ..]]);
+        assert_eq!(result.to_string(), dom.to_string());
+        assert_eq!(spans(&result), spans(&dom));
+    }
+    for size in 1 .. 8 {
+        let chunks: Vec<_> = bytes.chunks(size).collect();
+        assert_eq!(parse(&chunks).to_string(), dom.to_string());
+    }
+    assert_eq!(DOM::from_reader(bytes).unwrap().to_string(), dom.to_string());
+
+    // Large text and an unterminated tag in small chunks
+    let text = "Fish &amp; chips ".repeat(100_000);
+    let chunks: Vec<_> = text.as_bytes().chunks(100).collect();
+    assert_eq!(parse(&chunks).text(), DOM::new(&text).text());
+    let html = "<p title='".to_owned() + &text;
+    let chunks: Vec<_> = html.as_bytes().chunks(100).collect();
+    assert_eq!(parse(&chunks).to_string(), DOM::new(&html).to_string());
+
+    // XML declaration
+    let xml = b"  <?xml version=\"1.0\"?><Rss><br></Rss>";
+    let chunks: Vec<_> = xml.chunks(1).collect();
+    assert!(parse(&chunks).is_xml());
+    assert_eq!(parse(&chunks).to_string(), "  <?xml version=\"1.0\"?><Rss><br/></Rss>");
+    assert!(!parse(&[b"  <?x"]).is_xml());
+    let mut parser = Parser::new_xml();
+    parser.feed(b"<a/>");
+    assert_eq!(parser.finish().to_string(), "<a/>");
+
+    // Invalid UTF-8
+    assert_eq!(parse(&[b"<p>a\xff\xe2\x98", b"b</p>\xe2\x98"]).to_string(), "<p>a\u{FFFD}\u{FFFD}b</p>\u{FFFD}");
+}
+
+#[test]
+fn encoding_detection() {
+    // Byte order mark
+    let dom = DOM::from_bytes(b"\xef\xbb\xbf<meta charset=latin1><p>\xc3\xa4</p>");
+    assert_eq!((dom.at("p").unwrap().text(), dom.encoding()), ("ä".to_owned(), Some("UTF-8")));
+    let dom = DOM::from_bytes(b"\xff\xfe<\0p\0>\0\x16\x26");
+    assert_eq!((dom.at("p").unwrap().text(), dom.encoding()), ("☖".to_owned(), Some("UTF-16LE")));
+
+    // Meta elements
+    let dom = DOM::from_bytes(b"<html><head><META HTTP-EQUIV=\"content-type\" Content=\"text/html; Charset='koi8-r'\"></head><p>\xf0\xd2\xc9\xd7\xc5\xd4</p>");
+    assert_eq!((dom.at("p").unwrap().text(), dom.encoding()), ("Привет".to_owned(), Some("KOI8-R")));
+    let dom = DOM::from_bytes(b"<meta name=x content=\"charset=koi8-r\"><meta charset=\" ISO-8859-1 \"><p>caf\xe9</p>");
+    assert_eq!((dom.at("p").unwrap().text(), dom.encoding()), ("café".to_owned(), Some("windows-1252")));
+    assert_eq!(DOM::from_bytes(b"<meta charset=utf-16><p>\xc3\xa4</p>").encoding(), Some("UTF-8"));
+    assert_eq!(DOM::from_bytes(b"<script>'<meta charset=koi8-r>'</script>").encoding(), Some("UTF-8"));
+    let mut late = vec![b' '; 1024];
+    late.extend_from_slice(b"<meta charset=koi8-r>");
+    assert_eq!(DOM::from_bytes(&late).encoding(), Some("UTF-8"));
+
+    // Defaults
+    assert_eq!(DOM::from_bytes(b"<p>\xc3\xa4</p>").encoding(), Some("UTF-8"));
+    let dom = DOM::from_bytes(b"<p>caf\xe9</p>");
+    assert_eq!((dom.at("p").unwrap().text(), dom.encoding()), ("café".to_owned(), Some("windows-1252")));
+    assert_eq!(DOM::new("<p>").encoding(), None);
+
+    // Override
+    let dom = DOM::from_bytes_with_encoding(b"<meta charset=koi8-r><p>\xcf\xf0\xe8</p>", "cp1251");
+    assert_eq!((dom.at("p").unwrap().text(), dom.encoding()), ("При".to_owned(), Some("windows-1251")));
+    assert_eq!(DOM::from_bytes_with_encoding(b"<meta charset=koi8-r>", "unknown").encoding(), Some("KOI8-R"));
+    assert_eq!(DOM::from_bytes_with_encoding(b"\xef\xbb\xbf<p>", "koi8-r").encoding(), Some("UTF-8"));
+    assert!(DOM::from_bytes(b"<?xml version=\"1.0\"?><Feed/>").is_xml());
+}
+
+#[test]
+fn logical_pseudo_classes() {
+    let dom = DOM::new(r#"
+<div id="a">
+    <h1>A</h1>
+    <img class="hero">
+    <p>1 <em>2</em></p>
+</div>
+<div id="b">
+    <p><img class="hero"></p>
+    <span>3</span>
+</div>
+<div id="c"><h2>B</h2></div>
+<p id="d">4</p>
+    "#);
+
+    // ":is" and ":where"
+    let ids = |css: &str| dom.find(css).iter().map(|x| x.attr("id").unwrap_or_default()).collect::<Vec<_>>();
+    assert_eq!(dom.find(":is(h1, h2)").len(), 2);
+    assert_eq!(dom.find(":where(h1, h2)").len(), 2);
+    assert_eq!(dom.find("div :is(p, span) > em").len(), 1);
+    assert_eq!(dom.find(":is(#a, #b) > p").len(), 2);
+    assert_eq!(dom.find(":IS(div > p)").len(), 2);
+    assert_eq!(dom.at("#a").unwrap().find(":is(div p)").len(), 1);
+    assert_eq!(dom.at("#a").unwrap().find(":is(div p) em").len(), 1);
+
+    // ":has"
+    assert_eq!(ids("div:has(> img.hero)"), ["a"]);
+    assert_eq!(ids("div:has(img.hero)"), ["a", "b"]);
+    assert_eq!(ids("div:has(p > img)"), ["b"]);
+    assert_eq!(ids("div:has(> h1, > h2)"), ["a", "c"]);
+    assert_eq!(ids("div:has(+ div)"), ["a", "b"]);
+    assert_eq!(ids("div:has(+ div > h2)"), ["b"]);
+    assert_eq!(ids("div:has(~ p)"), ["a", "b", "c"]);
+    assert_eq!(ids(":has(em)"), ["a", ""]);
+    assert_eq!(ids("div:has(div)"), Vec::<String>::new());
+    assert_eq!(ids("div:has(> p:has(em))"), ["a"]);
+    assert_eq!(ids("div:has(:is(h1, span))"), ["a", "b"]);
+    assert_eq!(ids("div:not(:has(h1))"), ["b", "c"]);
+    assert_eq!(dom.find("p:has(em, [class=\")\"])").len(), 1);
+
+    // Invalid arguments
+    assert_eq!(Selector::parse(":has").unwrap_err().kind, SelectorErrorKind::InvalidArgument);
+    assert_eq!(Selector::parse(":has()").unwrap_err().kind, SelectorErrorKind::EmptySelector);
+    assert_eq!(Selector::parse(":has(>)").unwrap_err().kind, SelectorErrorKind::EmptySelector);
+    assert_eq!(Selector::parse(":is(> p)").unwrap_err().kind, SelectorErrorKind::EmptySelector);
+    assert_eq!(Selector::parse(":is(p").unwrap_err().kind, SelectorErrorKind::InvalidArgument);
+
+    // Unterminated arguments never match
+    for css in &[":is(", ":where(", ":has(", "div:is(h1", "div:has(> img"] {
+        assert!(dom.find(css).is_empty(), "{}", css);
+        assert!(dom.at(css).is_none(), "{}", css);
+        assert!(!dom.at("#a").unwrap().matches(css), "{}", css);
+    }
+}
+
+#[test]
+fn nth_child_of_selector() {
+    let dom = DOM::new(r#"
+<ul>
+    <li class="visible">A</li>
+    <li class="hidden">B</li>
+    <li class="visible">C</li>
+    <p class="visible">D</p>
+    <li class="hidden">E</li>
+    <li class="visible">F</li>
+</ul>
+<div><p>G</p><p class="x">H</p></div>
+    "#);
+    let texts = |css: &str| dom.find(css).iter().map(|x| x.text()).collect::<Vec<_>>();
+    assert_eq!(texts("li:nth-child(2 of .visible)"), ["C"]);
+    assert_eq!(texts(":nth-child(2 of .visible)"), ["C"]);
+    assert_eq!(texts(":nth-child(odd of .visible)"), ["A", "D"]);
+    assert_eq!(texts("li:nth-child(odd of li.visible)"), ["A", "F"]);
+    assert_eq!(texts(":nth-child(2n OF li:not(.visible), p)"), ["D", "H"]);
+    assert_eq!(texts(":nth-child(-n+2 of ul > .visible)"), ["A", "C"]);
+    assert_eq!(texts(":nth-last-child(1 of .visible)"), ["F"]);
+    assert_eq!(texts(":nth-last-child(2 of ul .hidden)"), ["B"]);
+    assert_eq!(texts("li:nth-child(3 of .hidden)"), Vec::<String>::new());
+    assert_eq!(texts("p:nth-child(1 of :not(.x))"), ["G"]);
+    assert_eq!(texts("div :nth-child(1 of p:has(+ .x))"), ["G"]);
+    assert_eq!(texts(":nth-child(odd of :nth-of-type(n+2))"), ["B", "E", "H"]);
+    assert_eq!(texts("li:nth-last-of-type(even)"), ["B", "E"]);
+
+    // Invalid arguments
+    assert_eq!(Selector::parse("li:nth-child(2 of)").unwrap_err().kind, SelectorErrorKind::InvalidArgument);
+    assert_eq!(Selector::parse("li:nth-child(2 of )").unwrap_err().kind, SelectorErrorKind::EmptySelector);
+    assert_eq!(Selector::parse("li:nth-child(x of .a)").unwrap_err().kind, SelectorErrorKind::InvalidArgument);
+    assert_eq!(Selector::parse("li:nth-of-type(2 of .a)").unwrap_err().kind, SelectorErrorKind::InvalidArgument);
+    assert_eq!(Selector::parse("li:nth-child(2 of .a[)").unwrap_err().kind, SelectorErrorKind::InvalidAttribute);
+}